@@ -0,0 +1,219 @@
+use std::{
+    fs, io,
+    path::{self, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    ffmpeg::ErrorKind,
+    server::cache::{format_size, frame_files, source_dirs, SourceStatus, SourceUsage},
+};
+
+/// Parses ages like `30d`, `12h` or `90m`.
+pub fn parse_age(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number * secs))
+}
+
+/// The cache directories for `file`, or all of them.
+fn select(root: &Path, file: Option<&str>) -> Result<Vec<SourceUsage>, ErrorKind> {
+    // a deleted source can't be canonicalized, but its directories still
+    // record where it was
+    let file = match file {
+        Some(file) => Some(fs::canonicalize(file).or_else(|_| path::absolute(file))?),
+        None => None,
+    };
+    let mut usages = Vec::new();
+    for dir in source_dirs(root)? {
+        let usage = SourceUsage::read(&dir)?;
+        if file.as_deref().is_none_or(|f| usage.is_for(f)) {
+            usages.push(usage);
+        }
+    }
+    Ok(usages)
+}
+
+/// Files directly under the root, left by versions that didn't keep a
+/// directory per source.
+fn loose_files(root: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    if root.is_dir() {
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                files.push((entry.path(), meta.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Splits off directories without a readable `source.json`. The root may be
+/// shared with other things, so those are only removed when forced.
+fn split_unknown(usages: Vec<SourceUsage>) -> (Vec<SourceUsage>, Vec<SourceUsage>) {
+    usages.into_iter().partition(|u| u.fingerprint.is_some())
+}
+
+/// Lists what was left alone for not looking like part of the cache.
+fn report_kept(unknown: &[SourceUsage], loose: &[(PathBuf, u64)]) {
+    for usage in unknown {
+        println!("kept {} (no source recorded)", usage.dir.to_string_lossy());
+    }
+    for (path, _) in loose {
+        println!("kept {} (loose file)", path.to_string_lossy());
+    }
+    if !unknown.is_empty() || !loose.is_empty() {
+        println!("pass --force to remove these too");
+    }
+}
+
+fn source_name(usage: &SourceUsage) -> String {
+    match &usage.fingerprint {
+        Some(f) => f.path.to_string_lossy().to_string(),
+        None => usage.dir.to_string_lossy().to_string(),
+    }
+}
+
+fn remove_dirs(usages: &[SourceUsage]) -> Result<u64, ErrorKind> {
+    let mut freed = 0;
+    for usage in usages {
+        fs::remove_dir_all(&usage.dir)?;
+        println!(
+            "removed {} ({}, {})",
+            source_name(usage),
+            usage.status,
+            format_size(usage.bytes)
+        );
+        freed += usage.bytes;
+    }
+    Ok(freed)
+}
+
+pub fn ls(root: &Path, file: Option<&str>) -> Result<(), ErrorKind> {
+    println!("{:<8} {:>8} {:>9}  SOURCE", "STATUS", "FRAMES", "SIZE");
+    for usage in select(root, file)? {
+        println!(
+            "{:<8} {:>8} {:>9}  {}",
+            usage.status.to_string(),
+            usage.frames,
+            format_size(usage.bytes),
+            source_name(&usage)
+        );
+    }
+    Ok(())
+}
+
+pub fn stats(root: &Path, file: Option<&str>) -> Result<(), ErrorKind> {
+    let usages = select(root, file)?;
+    let count = |status| usages.iter().filter(|u| u.status == status).count();
+    let frames: usize = usages.iter().map(|u| u.frames).sum();
+    let bytes: u64 = usages.iter().map(|u| u.bytes).sum();
+
+    println!("root      {}", root.to_string_lossy());
+    println!(
+        "sources   {} ({} current, {} changed, {} missing, {} unknown)",
+        usages.len(),
+        count(SourceStatus::Current),
+        count(SourceStatus::Changed),
+        count(SourceStatus::Missing),
+        count(SourceStatus::Unknown),
+    );
+    println!("frames    {frames}");
+    println!("size      {}", format_size(bytes));
+    if file.is_none() {
+        let loose = loose_files(root)?;
+        if !loose.is_empty() {
+            let size = loose.iter().map(|(_, size)| size).sum();
+            println!("loose     {} files, {}", loose.len(), format_size(size));
+        }
+    }
+    Ok(())
+}
+
+/// Removes directories whose source was deleted or has since changed. With
+/// `force`, also removes directories and files directly under the root that
+/// don't record a source, such as those left by older versions.
+pub fn gc(root: &Path, file: Option<&str>, force: bool) -> Result<(), ErrorKind> {
+    let (known, unknown) = split_unknown(select(root, file)?);
+    let orphaned: Vec<_> = known
+        .into_iter()
+        .filter(|u| matches!(u.status, SourceStatus::Changed | SourceStatus::Missing))
+        .collect();
+    let mut freed = remove_dirs(&orphaned)?;
+
+    if file.is_none() {
+        let loose = loose_files(root)?;
+        if force {
+            freed += remove_dirs(&unknown)?;
+            for (path, size) in loose {
+                fs::remove_file(&path)?;
+                println!("removed {}", path.to_string_lossy());
+                freed += size;
+            }
+        } else {
+            report_kept(&unknown, &loose);
+        }
+    }
+    println!("freed {}", format_size(freed));
+    Ok(())
+}
+
+/// Removes everything cached for the selected sources or, given an age,
+/// only the frames and audio written longer ago than that. Directories that
+/// don't record a source are only touched with `force`.
+pub fn purge(
+    root: &Path,
+    file: Option<&str>,
+    older_than: Option<Duration>,
+    force: bool,
+) -> Result<(), ErrorKind> {
+    let (mut usages, unknown) = split_unknown(select(root, file)?);
+    if force {
+        usages.extend(unknown);
+    } else {
+        report_kept(&unknown, &[]);
+    }
+    let freed = match older_than {
+        None => remove_dirs(&usages)?,
+        Some(age) => {
+            let cutoff = SystemTime::now()
+                .checked_sub(age)
+                .ok_or(ErrorKind::ArgumentError)?;
+            let mut freed = 0;
+            for usage in &usages {
+                let (mut removed, mut bytes) = (0, 0);
+                for (path, meta) in frame_files(&usage.dir)? {
+                    if meta.modified()? < cutoff {
+                        fs::remove_file(&path)?;
+                        removed += 1;
+                        bytes += meta.len();
+                    }
+                }
+                if removed > 0 {
+                    println!(
+                        "removed {removed} files of {} ({})",
+                        source_name(usage),
+                        format_size(bytes)
+                    );
+                }
+                freed += bytes;
+            }
+            freed
+        }
+    };
+    println!("freed {}", format_size(freed));
+    Ok(())
+}
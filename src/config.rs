@@ -0,0 +1,311 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{
+    ffmpeg::{cmd::FormatKind, ErrorKind},
+    server::cache::parse_size,
+};
+
+/// Settings for `frm`, read from defaults, then the config file, then
+/// `CACHE_DIR`, then command-line flags, each overriding the last.
+///
+/// The config file is TOML, by default `$XDG_CONFIG_HOME/frm/config.toml`:
+///
+/// ```toml
+/// [serve]
+/// host = "127.0.0.1"
+/// port = 3030
+/// memory_cache = 1200
+///
+/// [cache]
+/// dir = "~/.cache/frm"
+/// max = "5GB"
+/// format = "webp"
+///
+/// [log]
+/// level = "debug"
+/// file = "~/frm.log"
+/// format = "json"
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+
+    /// Frames kept in memory, shared evenly between the videos open at once
+    /// in library mode
+    pub memory_cache: usize,
+
+    pub cache_dir: PathBuf,
+    pub cache_max: Option<u64>,
+    pub cache_format: FormatKind,
+
+    pub log_level: LevelFilter,
+
+    /// Appended to, or stderr if unset
+    pub log_file: Option<PathBuf>,
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The config file as written. Everything is optional, so missing tables
+/// and keys leave the defaults in place.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    serve: ServeTable,
+    cache: CacheTable,
+    log: LogTable,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServeTable {
+    host: Option<String>,
+    port: Option<u16>,
+    memory_cache: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheTable {
+    dir: Option<String>,
+    max: Option<Size>,
+    format: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogTable {
+    level: Option<String>,
+    file: Option<String>,
+    format: Option<String>,
+}
+
+/// A byte count, or a size like `"5GB"`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Float(f64),
+    Text(String),
+}
+
+impl Size {
+    fn bytes(&self) -> Option<u64> {
+        match self {
+            Size::Bytes(n) => Some(*n),
+            Size::Float(n) if n.is_finite() && *n >= 0.0 => Some(n.round() as u64),
+            Size::Float(_) => None,
+            Size::Text(s) => parse_size(s),
+        }
+    }
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+/// `$var`, or `~/fallback` if that's unset or empty.
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home().unwrap_or_default().join(fallback),
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+pub fn parse_format(name: &str) -> Option<FormatKind> {
+    match name {
+        "png" => Some(FormatKind::Png),
+        "jpeg" => Some(FormatKind::Jpeg),
+        "webp" => Some(FormatKind::WebP),
+        _ => None,
+    }
+}
+
+pub fn parse_log_format(name: &str) -> Option<LogFormat> {
+    match name {
+        "text" => Some(LogFormat::Text),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".into(),
+            port: 3030,
+            memory_cache: 1200,
+            cache_dir: xdg_dir("XDG_CACHE_HOME", ".cache").join("frm"),
+            cache_max: None,
+            cache_format: FormatKind::Png,
+            log_level: LevelFilter::INFO,
+            log_file: None,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        xdg_dir("XDG_CONFIG_HOME", ".config")
+            .join("frm")
+            .join("config.toml")
+    }
+
+    /// Defaults overridden by the config file at `path`, if given, or at
+    /// the default path if one exists there, and then by `CACHE_DIR`.
+    pub fn load(path: Option<&Path>) -> Result<Self, ErrorKind> {
+        let mut config = Config::default();
+
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => (Config::default_path(), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => config.apply_file(&text).map_err(|e| {
+                ErrorKind::Unhandled(format!("config {}: {e}", path.to_string_lossy()))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if let Some(dir) = env::var_os("CACHE_DIR").filter(|d| !d.is_empty()) {
+            config.cache_dir = PathBuf::from(dir);
+        }
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, text: &str) -> Result<(), String> {
+        let file: File = toml::from_str(text).map_err(|e| e.to_string())?;
+        let invalid = |key: &str| format!("invalid value for {key}");
+
+        let serve = file.serve;
+        if let Some(host) = serve.host {
+            self.host = host;
+        }
+        if let Some(port) = serve.port {
+            self.port = port;
+        }
+        match serve.memory_cache {
+            Some(0) => return Err(invalid("serve.memory_cache")),
+            Some(n) => self.memory_cache = n,
+            None => {}
+        }
+
+        let cache = file.cache;
+        if let Some(dir) = cache.dir {
+            self.cache_dir = expand_home(&dir);
+        }
+        if let Some(max) = cache.max {
+            self.cache_max = Some(max.bytes().ok_or_else(|| invalid("cache.max"))?);
+        }
+        if let Some(format) = cache.format {
+            self.cache_format = parse_format(&format).ok_or_else(|| invalid("cache.format"))?;
+        }
+
+        let log = file.log;
+        if let Some(level) = log.level {
+            self.log_level = level.parse().map_err(|_| invalid("log.level"))?;
+        }
+        if let Some(file) = log.file {
+            self.log_file = Some(expand_home(&file));
+        }
+        if let Some(format) = log.format {
+            self.log_format = parse_log_format(&format).ok_or_else(|| invalid("log.format"))?;
+        }
+        Ok(())
+    }
+
+    /// Creates the cache directory if it doesn't exist yet.
+    pub fn ensure_cache_dir(&self) -> io::Result<&Path> {
+        fs::create_dir_all(&self.cache_dir)?;
+        Ok(&self.cache_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.apply_file(text).map(|_| config)
+    }
+
+    #[test]
+    fn reads_every_table() {
+        let config = apply(
+            r#"
+            # comments and blank lines are fine
+            [serve]
+            host = "0.0.0.0" # even here
+            port = 8080
+            memory_cache = 300
+
+            [cache]
+            dir = '/tmp/frm#cache'
+            max = "5GB"
+            format = "webp"
+
+            [log]
+            level = "debug"
+            format = "json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.memory_cache, 300);
+        assert_eq!(config.cache_dir, PathBuf::from("/tmp/frm#cache"));
+        assert_eq!(config.cache_max, Some(5_000_000_000));
+        assert_eq!(config.cache_format, FormatKind::WebP);
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn cache_max_takes_any_number() {
+        let max = |text| apply(text).unwrap().cache_max;
+        assert_eq!(max("cache.max = 1_000_000"), Some(1_000_000));
+        assert_eq!(max("[cache]\nmax = 5.5"), Some(6));
+        assert_eq!(max("[cache]\nmax = 2.5e9"), Some(2_500_000_000));
+        assert!(apply("[cache]\nmax = -1.0").is_err());
+        assert!(apply("[cache]\nmax = \"lots\"").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        assert!(apply("[serve]\nhots = \"x\"").is_err());
+        assert!(apply("[serve]\nport = 70000").is_err());
+        assert!(apply("[serve]\nmemory_cache = 0").is_err());
+        assert!(apply("[cache]\nformat = \"gif\"").is_err());
+        assert!(apply("[log]\nlevel = \"loud\"").is_err());
+        assert!(apply("[serve]\nport = 1\nport = 2").is_err());
+    }
+
+    #[test]
+    fn missing_tables_keep_defaults() {
+        let config = apply("").unwrap();
+        assert_eq!(config.port, Config::default().port);
+        assert_eq!(config.cache_max, None);
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::cmd::*;
+use super::path::existing_path;
+use super::proc;
+use super::ErrorKind;
+
+/// Shortest run of black frames or silence worth reporting, in seconds
+const MIN_DURATION: f32 = 0.5;
+
+/// Fraction of a pixel's range under which it counts as black
+const BLACK_PIXEL_THRESHOLD: f32 = 0.10;
+
+/// Level under which audio counts as silent
+const SILENCE_DB: i32 = -50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interval {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Black and silent stretches of a file, and the breaks where they overlap.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Breaks {
+    pub black: Vec<Interval>,
+    pub silence: Vec<Interval>,
+    pub breaks: Vec<Interval>,
+}
+
+impl Breaks {
+    /// Only the intervals that overlap `from..to`.
+    pub fn within(&self, from: usize, to: usize) -> Self {
+        let overlapping = |is: &Vec<Interval>| {
+            is.iter()
+                .filter(|i| i.start < to && i.end > from)
+                .copied()
+                .collect()
+        };
+        Breaks {
+            black: overlapping(&self.black),
+            silence: overlapping(&self.silence),
+            breaks: overlapping(&self.breaks),
+        }
+    }
+}
+
+/// Runs `blackdetect` and `silencedetect` across a whole file.
+#[derive(Debug, Clone)]
+pub struct BreakDetect {
+    origin: PathBuf,
+    start_time: Duration,
+}
+
+impl BreakDetect {
+    /// `start_time` is the file's, which frame timestamps are offset by.
+    pub fn new(origin: &str, start_time: Duration) -> Result<Self, ErrorKind> {
+        Ok(BreakDetect {
+            origin: existing_path(origin)?.to_path_buf(),
+            start_time,
+        })
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn read(&self) -> Result<Breaks, ErrorKind> {
+        let log = proc::dump_stderr(self.execute()?)?;
+        Ok(parse_break_log(&log, self.start_time))
+    }
+}
+
+impl Command for BreakDetect {
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+        vec![
+            LogLevel(Level::Info),
+            Positional("-nostats".into()),
+            Input(self.origin.to_string_lossy().to_string()),
+            Named("-map".into(), "0:v:0".into()),
+            Named("-map".into(), "0:a:0?".into()),
+            Positional("-sn".into()),
+            Named(
+                "-vf".into(),
+                format!("scale=320:-2,blackdetect=d={MIN_DURATION}:pix_th={BLACK_PIXEL_THRESHOLD}"),
+            ),
+            Named(
+                "-af".into(),
+                format!("silencedetect=n={SILENCE_DB}dB:d={MIN_DURATION}"),
+            ),
+            Format(FormatKind::Null),
+            Output(Destination::Stdout),
+        ]
+        .into_iter()
+        .flat_map(CommandOption::process_option)
+        .collect()
+    }
+}
+
+// [blackdetect @ 0x55d1] black_start:1290.29 black_end:1292.04 black_duration:1.75
+// [silencedetect @ 0x55d2] silence_start: 1290.1
+// [silencedetect @ 0x55d2] silence_end: 1292.3 | silence_duration: 2.2
+
+fn field_ms(line: &str, key: &str, start: f64) -> Option<usize> {
+    let (_, rest) = line.split_once(key)?;
+    let secs: f64 = rest.split_whitespace().next()?.parse().ok()?;
+    Some(((secs - start).max(0.0) * 1000.0).round() as usize)
+}
+
+/// Timestamps are shifted back by `start_time` so they line up with seeks.
+pub fn parse_break_log(log: &str, start_time: Duration) -> Breaks {
+    let start = start_time.as_secs_f64();
+    let field_ms = |line, key| field_ms(line, key, start);
+    let mut black = Vec::new();
+    let mut silence = Vec::new();
+    let mut silence_start = None;
+
+    for line in log.lines() {
+        if line.contains("black_start:") {
+            if let (Some(start), Some(end)) =
+                (field_ms(line, "black_start:"), field_ms(line, "black_end:"))
+            {
+                black.push(Interval { start, end });
+            }
+        } else if line.contains("silence_start:") {
+            silence_start = field_ms(line, "silence_start:");
+        } else if line.contains("silence_end:") {
+            // a silence still running at the end of the file is never closed
+            if let (Some(start), Some(end)) = (silence_start.take(), field_ms(line, "silence_end:"))
+            {
+                silence.push(Interval { start, end });
+            }
+        }
+    }
+
+    let breaks = merge_overlaps(&black, &silence);
+    Breaks {
+        black,
+        silence,
+        breaks,
+    }
+}
+
+/// Each black interval that overlaps a silent one, widened to cover both,
+/// with overlapping results merged.
+fn merge_overlaps(black: &[Interval], silence: &[Interval]) -> Vec<Interval> {
+    let mut merged: Vec<Interval> = black
+        .iter()
+        .flat_map(|b| {
+            silence
+                .iter()
+                .filter(move |s| s.start < b.end && s.end > b.start)
+                .map(move |s| Interval {
+                    start: b.start.min(s.start),
+                    end: b.end.max(s.end),
+                })
+        })
+        .collect();
+    merged.sort_by_key(|i| i.start);
+
+    let mut out: Vec<Interval> = Vec::new();
+    for i in merged {
+        match out.last_mut() {
+            Some(last) if i.start <= last.end => last.end = last.end.max(i.end),
+            _ => out.push(i),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(start: usize, end: usize) -> Interval {
+        Interval { start, end }
+    }
+
+    #[test]
+    fn parses_black_and_silence_relative_to_the_start() {
+        let log = "\
+[blackdetect @ 0x55d1] black_start:1291.69 black_end:1293.44 black_duration:1.75
+[silencedetect @ 0x55d2] silence_start: 1291.5
+[silencedetect @ 0x55d2] silence_end: 1293.7 | silence_duration: 2.2
+[blackdetect @ 0x55d1] black_start:1801.4 black_end:1802.4 black_duration:1
+[silencedetect @ 0x55d2] silence_start: 2001.4
+";
+        let breaks = parse_break_log(log, Duration::from_secs_f64(1.4));
+        assert_eq!(
+            breaks.black,
+            vec![
+                interval(1_290_290, 1_292_040),
+                interval(1_800_000, 1_801_000)
+            ]
+        );
+        // the last silence runs to the end of the file and is never closed
+        assert_eq!(breaks.silence, vec![interval(1_290_100, 1_292_300)]);
+        assert_eq!(breaks.breaks, vec![interval(1_290_100, 1_292_300)]);
+    }
+
+    #[test]
+    fn merges_overlapping_breaks() {
+        let black = [interval(100, 200), interval(250, 300), interval(900, 1000)];
+        let silence = [interval(150, 260), interval(500, 600)];
+        assert_eq!(merge_overlaps(&black, &silence), vec![interval(100, 300)]);
+
+        // touching but not overlapping isn't a break
+        assert!(merge_overlaps(&[interval(0, 100)], &[interval(100, 200)]).is_empty());
+        assert!(merge_overlaps(&black, &[]).is_empty());
+    }
+}
@@ -0,0 +1,13 @@
+use serde::Serialize;
+
+/// A chapter marker embedded in the container.
+#[derive(Debug, Clone, Serialize)]
+pub struct Chapter {
+    /// Position among the file's chapters. ffprobe's own ids are chapter
+    /// UIDs for Matroska, which are neither small nor ordered.
+    pub id: usize,
+
+    pub start: usize,
+    pub end: usize,
+    pub title: Option<String>,
+}
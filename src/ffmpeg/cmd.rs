@@ -17,6 +17,7 @@ pub trait Command: Debug {
 #[derive(Debug, Clone, Copy)]
 pub enum Level {
     Error,
+    Info,
 }
 
 #[derive(Debug, Clone)]
@@ -25,18 +26,20 @@ pub enum Destination {
     Path(PathBuf),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum FormatKind {
     Png,
-    Gif,
     Jpeg,
+    WebP,
+    Avif,
     Srt,
-}
-
-#[derive(Debug, Clone)]
-pub enum Dim {
-    H(u32),
-    W(u32),
+    WebVtt,
+    Ass,
+    Mp4,
+    Ogg,
+    Wav,
+    Pcm,
+    Null,
 }
 
 #[derive(Debug, Clone)]
@@ -47,8 +50,8 @@ pub enum CommandOption {
     Position(usize),
     Duration(usize),
     Input(String),
-    Scale(Dim),
     Frames(usize),
+    VideoCodec(String),
     Format(FormatKind),
     Output(Destination),
 }
@@ -57,6 +60,7 @@ impl fmt::Display for Level {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Level::Error => write!(f, "error"),
+            Level::Info => write!(f, "info"),
         }
     }
 }
@@ -64,10 +68,70 @@ impl fmt::Display for Level {
 impl fmt::Display for FormatKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            FormatKind::Png => write!(f, "apng"),
-            FormatKind::Gif => write!(f, "gif"),
-            FormatKind::Jpeg => write!(f, "singlejpeg"),
+            FormatKind::Png | FormatKind::Jpeg | FormatKind::WebP => write!(f, "image2pipe"),
+            FormatKind::Avif => write!(f, "avif"),
             FormatKind::Srt => write!(f, "srt"),
+            FormatKind::WebVtt => write!(f, "webvtt"),
+            FormatKind::Ass => write!(f, "ass"),
+            FormatKind::Mp4 => write!(f, "mp4"),
+            FormatKind::Ogg => write!(f, "ogg"),
+            FormatKind::Wav => write!(f, "wav"),
+            FormatKind::Pcm => write!(f, "s16le"),
+            FormatKind::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl FormatKind {
+    /// File extension for output in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FormatKind::Png => "png",
+            FormatKind::Jpeg => "jpg",
+            FormatKind::WebP => "webp",
+            FormatKind::Avif => "avif",
+            FormatKind::Srt => "srt",
+            FormatKind::WebVtt => "vtt",
+            FormatKind::Ass => "ass",
+            FormatKind::Mp4 => "mp4",
+            FormatKind::Ogg => "ogg",
+            FormatKind::Wav => "wav",
+            FormatKind::Pcm => "pcm",
+            FormatKind::Null => "null",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FormatKind::Png => "image/png",
+            FormatKind::Jpeg => "image/jpeg",
+            FormatKind::WebP => "image/webp",
+            FormatKind::Avif => "image/avif",
+            FormatKind::Srt => "application/x-subrip",
+            FormatKind::WebVtt => "text/vtt",
+            FormatKind::Ass => "text/x-ssa",
+            FormatKind::Mp4 => "video/mp4",
+            FormatKind::Ogg => "audio/ogg",
+            FormatKind::Wav => "audio/wav",
+            FormatKind::Pcm | FormatKind::Null => "application/octet-stream",
+        }
+    }
+
+    /// Whether the muxer seeks back over what it wrote, which rules out
+    /// writing to a pipe.
+    pub fn needs_seekable_output(&self) -> bool {
+        matches!(self, FormatKind::Avif)
+    }
+
+    /// Encoder for still images in this format. Piped images need it named,
+    /// since only files get one picked from their extension.
+    pub fn image_codec(&self) -> Option<&'static str> {
+        match self {
+            FormatKind::Png => Some("png"),
+            FormatKind::Jpeg => Some("mjpeg"),
+            FormatKind::WebP => Some("libwebp"),
+            FormatKind::Avif => Some("libaom-av1"),
+            _ => None,
         }
     }
 }
@@ -82,8 +146,7 @@ impl CommandOption {
             Input(p) => (Some("-i".into()), p),
             Frames(n) => (Some("-vframes".into()), format!("{}", n)),
             // Filter(filter) => (Some("-vf".into()), filter.into()),
-            Scale(Dim::W(n)) => (Some("-vf".into()), format!("scale={}:-1", n)),
-            Scale(Dim::H(n)) => (Some("-vf".into()), format!("scale=-1:{}", n)),
+            VideoCodec(codec) => (Some("-c:v".into()), codec),
             Format(format) => (Some("-f".into()), format!("{}", format)),
             Output(Destination::Stdout) => (None, String::from("-")),
             Output(Destination::Path(s)) => (None, s.to_string_lossy().to_string()),
@@ -0,0 +1,366 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tracing::info;
+
+use super::cmd::*;
+use super::path::existing_path;
+use super::{keyframes, proc, stream_info, ErrorKind, StreamInfo};
+
+/// Removes the intermediate files of a cut once the cut is dropped.
+#[derive(Debug)]
+struct WorkDir(PathBuf);
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            info!("failed to remove {}: {e}", self.0.to_string_lossy());
+        }
+    }
+}
+
+/// A video-only piece of the cut, either copied or re-encoded.
+#[derive(Debug, Clone)]
+struct Segment {
+    origin: PathBuf,
+    start: usize,
+    end: usize,
+
+    /// Encoder and its arguments; `None` copies packets as-is
+    encoder: Option<(&'static str, Vec<String>)>,
+    pix_fmt: Option<String>,
+    output: PathBuf,
+}
+
+impl Command for Segment {
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+
+        let mut options = vec![
+            LogLevel(Level::Error),
+            Position(self.start),
+            Input(self.origin.to_string_lossy().to_string()),
+            Duration(self.end - self.start),
+            Named("-map".into(), "0:v:0".into()),
+        ];
+        match &self.encoder {
+            None => options.push(VideoCodec("copy".into())),
+            Some((encoder, args)) => {
+                options.push(VideoCodec((*encoder).into()));
+                options.extend(args.iter().cloned().map(Positional));
+                if let Some(pix_fmt) = &self.pix_fmt {
+                    options.push(Named("-pix_fmt".into(), pix_fmt.clone()));
+                }
+            }
+        }
+        // MPEG-TS carries parameter sets in-band, so segments encoded with
+        // different settings still decode after concatenation
+        options.push(Named("-f".into(), "mpegts".into()));
+        options.push(Output(Destination::Path(self.output.clone())));
+
+        options
+            .into_iter()
+            .flat_map(CommandOption::process_option)
+            .collect()
+    }
+}
+
+/// All of the source's audio in range, copied.
+#[derive(Debug, Clone)]
+struct AudioCopy {
+    origin: PathBuf,
+    start: usize,
+    end: usize,
+    output: PathBuf,
+}
+
+impl Command for AudioCopy {
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+        vec![
+            LogLevel(Level::Error),
+            Position(self.start),
+            Input(self.origin.to_string_lossy().to_string()),
+            Duration(self.end - self.start),
+            Named("-map".into(), "0:a".into()),
+            Named("-c".into(), "copy".into()),
+            Named("-f".into(), "matroska".into()),
+            Output(Destination::Path(self.output.clone())),
+        ]
+        .into_iter()
+        .flat_map(CommandOption::process_option)
+        .collect()
+    }
+}
+
+/// Joins the video segments and the audio into one Matroska stream.
+#[derive(Debug, Clone)]
+struct Mux {
+    segments: PathBuf,
+    audio: Option<PathBuf>,
+}
+
+impl Command for Mux {
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+
+        let mut options = vec![
+            LogLevel(Level::Error),
+            Named("-f".into(), "concat".into()),
+            Named("-safe".into(), "0".into()),
+            Input(self.segments.to_string_lossy().to_string()),
+        ];
+        if let Some(audio) = &self.audio {
+            options.push(Input(audio.to_string_lossy().to_string()));
+            options.push(Named("-map".into(), "1:a".into()));
+        }
+        options.extend(vec![
+            Named("-map".into(), "0:v".into()),
+            Named("-c".into(), "copy".into()),
+            Named("-f".into(), "matroska".into()),
+            Output(Destination::Stdout),
+        ]);
+
+        options
+            .into_iter()
+            .flat_map(CommandOption::process_option)
+            .collect()
+    }
+}
+
+/// Encoders that can produce a stream concatenable with the source's.
+fn matching_encoder(codec: &str) -> Option<(&'static str, Vec<String>)> {
+    let args = |crf: &str| {
+        vec!["-crf", crf, "-preset", "fast"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    };
+    match codec {
+        "h264" => Some(("libx264", args("16"))),
+        "hevc" => Some(("libx265", args("18"))),
+        _ => None,
+    }
+}
+
+fn fallback_encoder() -> (&'static str, Vec<String>) {
+    matching_encoder("h264").expect("h264 has an encoder")
+}
+
+/// A cut whose intermediate files are ready to be muxed.
+#[derive(Debug)]
+pub struct SmartCut {
+    mux: Mux,
+    lossless: bool,
+    dir: WorkDir,
+}
+
+impl SmartCut {
+    /// Copies packets between the first and last keyframes in `start..end`
+    /// and re-encodes only the partial GOPs at either edge. Sources whose
+    /// codec can't be matched are re-encoded in full. `start_time` is the
+    /// file's, which keyframe timestamps are offset by.
+    #[tracing::instrument(skip(origin, dir))]
+    pub fn prepare(
+        origin: &Path,
+        dir: PathBuf,
+        start: usize,
+        end: usize,
+        start_time: Duration,
+    ) -> Result<Self, ErrorKind> {
+        let origin_str = origin.to_string_lossy();
+        let origin = existing_path(&origin_str)?.to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let dir = WorkDir(dir);
+
+        let video = stream_info(&origin_str, "v:0")?
+            .ok_or_else(|| ErrorKind::Unhandled("no video stream to cut".into()))?;
+        let audio = stream_info(&origin_str, "a:0")?;
+
+        let plan = plan_segments(&origin_str, &video, start, end, start_time)?;
+        let lossless = plan.iter().all(|(_, _, copy)| *copy);
+
+        let encoder = matching_encoder(&video.codec).unwrap_or_else(fallback_encoder);
+        let mut list = String::new();
+        for (n, (seg_start, seg_end, copy)) in plan.into_iter().enumerate() {
+            let segment = Segment {
+                origin: origin.clone(),
+                start: seg_start,
+                end: seg_end,
+                encoder: if copy { None } else { Some(encoder.clone()) },
+                pix_fmt: video.pix_fmt.clone(),
+                output: dir.0.join(format!("{n:02}.ts")),
+            };
+            segment.execute()?;
+            list.push_str(&format!(
+                "file '{}'\n",
+                segment.output.to_string_lossy().replace('\'', "'\\''")
+            ));
+        }
+        let segments = dir.0.join("segments.txt");
+        fs::write(&segments, list)?;
+
+        let audio = if audio.is_some() {
+            let copy = AudioCopy {
+                origin,
+                start,
+                end,
+                output: dir.0.join("audio.mka"),
+            };
+            copy.execute()?;
+            Some(copy.output)
+        } else {
+            None
+        };
+
+        Ok(SmartCut {
+            mux: Mux { segments, audio },
+            lossless,
+            dir,
+        })
+    }
+
+    /// Whether every frame was copied rather than re-encoded.
+    pub fn lossless(&self) -> bool {
+        self.lossless
+    }
+
+    /// Muxes the result, yielding it as it's written. The intermediate files
+    /// are removed once the stream is dropped.
+    pub fn stream(self) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, ErrorKind> {
+        let dir = self.dir;
+        let output = proc::stream("ffmpeg", self.mux.build())?;
+        Ok(output.map(move |chunk| {
+            let _keep = &dir;
+            chunk
+        }))
+    }
+}
+
+/// Splits `start..end` (ms) into `(start, end, copy)` segments. Keyframe
+/// times are rounded inward so a copy never starts before its keyframe.
+/// `start_time` is the file's, which packet timestamps are offset by.
+fn plan_segments(
+    origin: &str,
+    video: &StreamInfo,
+    start: usize,
+    end: usize,
+    start_time: Duration,
+) -> Result<Vec<(usize, usize, bool)>, ErrorKind> {
+    if matching_encoder(&video.codec).is_none() {
+        return Ok(vec![(start, end, false)]);
+    }
+    let offset = start_time.as_millis() as usize;
+    Ok(split_at_keyframes(
+        &keyframes(origin, Some((start + offset, end + offset)))?,
+        start_time,
+        start,
+        end,
+    ))
+}
+
+/// Copies from the first keyframe in range to the last, re-encoding the
+/// pieces either side. The leading piece stops short of the first keyframe,
+/// which the copy starts on, so no frame appears twice.
+fn split_at_keyframes(
+    keyframes: &[Duration],
+    start_time: Duration,
+    start: usize,
+    end: usize,
+) -> Vec<(usize, usize, bool)> {
+    let ms = |d: &Duration| d.saturating_sub(start_time).as_secs_f64() * 1000.0;
+    let first = keyframes
+        .iter()
+        .map(ms)
+        .find(|k| (start..end).contains(&(k.ceil() as usize)));
+    let last = keyframes
+        .iter()
+        .rev()
+        .map(|k| ms(k).floor() as usize)
+        .find(|k| *k > start && *k <= end);
+
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) if (first.ceil() as usize) < last => (first, last),
+        _ => return vec![(start, end, false)],
+    };
+
+    vec![
+        (start, first.floor() as usize, false),
+        (first.ceil() as usize, last, true),
+        (last, end, false),
+    ]
+    .into_iter()
+    .filter(|(s, e, _)| e > s)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(keyframes: &[f64]) -> Vec<Duration> {
+        keyframes
+            .iter()
+            .map(|s| Duration::from_secs_f64(*s))
+            .collect()
+    }
+
+    #[test]
+    fn copies_between_keyframes() {
+        let keyframes = secs(&[0.0, 2.0025, 4.0045, 6.0065]);
+        assert_eq!(
+            split_at_keyframes(&keyframes, Duration::ZERO, 1000, 5000),
+            vec![(1000, 2002, false), (2003, 4004, true), (4004, 5000, false)]
+        );
+    }
+
+    #[test]
+    fn skips_empty_pieces_on_keyframes() {
+        let keyframes = secs(&[0.0, 2.0, 4.0, 6.0]);
+        assert_eq!(
+            split_at_keyframes(&keyframes, Duration::ZERO, 2000, 6000),
+            vec![(2000, 6000, true)]
+        );
+    }
+
+    #[test]
+    fn re_encodes_without_two_keyframes() {
+        let keyframes = secs(&[0.0, 2.0, 10.0]);
+        assert_eq!(
+            split_at_keyframes(&keyframes, Duration::ZERO, 3000, 5000),
+            vec![(3000, 5000, false)]
+        );
+        assert_eq!(
+            split_at_keyframes(&keyframes, Duration::ZERO, 1000, 5000),
+            vec![(1000, 5000, false)]
+        );
+        assert_eq!(
+            split_at_keyframes(&[], Duration::ZERO, 0, 5000),
+            vec![(0, 5000, false)]
+        );
+    }
+
+    #[test]
+    fn keyframes_are_relative_to_the_start() {
+        // a transport stream whose timestamps start at 1.4s
+        let keyframes = secs(&[1.4, 3.4025, 5.4045, 7.4065]);
+        let start_time = Duration::from_secs_f64(1.4);
+        assert_eq!(
+            split_at_keyframes(&keyframes, start_time, 1000, 5000),
+            vec![(1000, 2002, false), (2003, 4004, true), (4004, 5000, false)]
+        );
+    }
+
+    #[test]
+    fn leading_edge_stops_before_the_keyframe() {
+        // the keyframe frame belongs to the copy alone, even on a whole ms
+        let keyframes = secs(&[0.0, 2.0, 4.0]);
+        assert_eq!(
+            split_at_keyframes(&keyframes, Duration::ZERO, 1000, 4500),
+            vec![(1000, 2000, false), (2000, 4000, true), (4000, 4500, false)]
+        );
+    }
+}
@@ -26,6 +26,9 @@ pub enum ErrorKind {
     #[error("server error: {0}")]
     Server(#[from] server::Error),
 
+    #[error("unsupported subtitle codec: {0}")]
+    UnsupportedSubtitles(String),
+
     #[error("unhandled error: {0}")]
     Unhandled(String),
 }
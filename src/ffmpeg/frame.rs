@@ -1,5 +1,10 @@
+use std::env;
+use std::fs;
 use std::io;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
 
 use super::cmd::*;
 use super::path::non_existing_path;
@@ -17,41 +22,234 @@ impl ImageData {
     }
 }
 
+/// How a frame given both a width and a height is scaled into them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Fits inside the box, keeping its aspect ratio
+    #[default]
+    Contain,
+
+    /// Fills the box exactly, trimming whatever overflows it
+    Cover,
+}
+
+impl Fit {
+    fn name(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        }
+    }
+}
+
+/// A rectangle of the source frame, in source pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Crop {
+    /// Parses `W:H:X:Y`, the order ffmpeg's crop filter takes.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(':').map(|p| p.trim().parse::<u32>().ok());
+        let crop = Crop {
+            width: parts.next()??,
+            height: parts.next()??,
+            x: parts.next()??,
+            y: parts.next()??,
+        };
+        let valid = parts.next().is_none() && crop.width > 0 && crop.height > 0;
+        valid.then_some(crop)
+    }
+
+    /// Whether the rectangle lies inside a frame of the given size.
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.x.checked_add(self.width).is_some_and(|r| r <= width)
+            && self.y.checked_add(self.height).is_some_and(|b| b <= height)
+    }
+}
+
+/// How a frame is rendered. Frames rendered differently are cached apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Render {
+    /// Output size. With neither set, the frame or crop keeps its own.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+
+    /// Taken before scaling
+    pub crop: Option<Crop>,
+
+    pub format: FormatKind,
+
+    /// From 1 to 100, for lossy formats. Unset means the format's default.
+    pub quality: Option<u8>,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Render {
+            width: Some(640),
+            height: None,
+            fit: Fit::default(),
+            crop: None,
+            format: FormatKind::Png,
+            quality: None,
+        }
+    }
+}
+
+impl Render {
+    /// Names the cache directory for frames rendered this way.
+    pub fn key(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(c) = self.crop {
+            parts.push(format!("c{}x{}+{}+{}", c.width, c.height, c.x, c.y));
+        }
+        parts.push(match (self.width, self.height) {
+            (Some(w), Some(h)) => format!("w{w}-h{h}-{}", self.fit.name()),
+            (Some(w), None) => format!("w{w}"),
+            (None, Some(h)) => format!("h{h}"),
+            (None, None) => "full".into(),
+        });
+        if let (Some(q), Some(_)) = (self.quality, self.effective_quality()) {
+            parts.push(format!("q{q}"));
+        }
+        parts.push(self.format.extension().into());
+        parts.join("-")
+    }
+
+    /// The quality encoded at, or `None` for lossless formats.
+    fn effective_quality(&self) -> Option<u32> {
+        let default = match self.format {
+            FormatKind::Jpeg => 95,
+            FormatKind::WebP => 80,
+            FormatKind::Avif => 50,
+            _ => return None,
+        };
+        Some(self.quality.map_or(default, |q| q.clamp(1, 100)).into())
+    }
+
+    /// The filter chain that crops and scales a decoded frame, if it needs
+    /// either.
+    pub fn filter(&self) -> Option<String> {
+        let mut filters = Vec::new();
+        if let Some(c) = self.crop {
+            filters.push(format!("crop={}:{}:{}:{}", c.width, c.height, c.x, c.y));
+        }
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => match self.fit {
+                Fit::Contain => filters.push(format!(
+                    "scale={w}:{h}:force_original_aspect_ratio=decrease"
+                )),
+                Fit::Cover => {
+                    filters.push(format!(
+                        "scale={w}:{h}:force_original_aspect_ratio=increase"
+                    ));
+                    filters.push(format!("crop={w}:{h}"));
+                }
+            },
+            (Some(w), None) => filters.push(format!("scale={w}:-1")),
+            (None, Some(h)) => filters.push(format!("scale=-1:{h}")),
+            (None, None) => {}
+        }
+        (!filters.is_empty()).then(|| filters.join(","))
+    }
+
+    /// Encoder settings for lossy formats, by default tuned for a few dozen
+    /// KB a frame.
+    pub fn encoder_options(&self) -> Vec<CommandOption> {
+        use CommandOption::*;
+        let quality = match self.effective_quality() {
+            Some(q) => q,
+            None => return vec![],
+        };
+        match self.format {
+            // qscale runs from 2, the best, to 31
+            FormatKind::Jpeg => vec![Named(
+                "-q:v".into(),
+                (2 + (100 - quality) * 29 / 99).to_string(),
+            )],
+            FormatKind::WebP => vec![Named("-quality".into(), quality.to_string())],
+            // crf runs from 0, lossless, to 63
+            FormatKind::Avif => vec![
+                Named("-crf".into(), ((100 - quality) * 63 / 100).to_string()),
+                Named("-cpu-used".into(), "6".into()),
+                Named("-still-picture".into(), "1".into()),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+static SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     timecode: usize,
     data: Option<ImageData>,
     origin: PathBuf,
-    encoding: FormatKind,
+    render: Render,
+
+    /// Written to instead of stdout by formats that can't be piped
+    scratch: Option<PathBuf>,
 }
 
 impl Command for Frame {
     fn build(&self) -> Vec<String> {
         use CommandOption::*;
-        vec![
+        let mut options = vec![
             LogLevel(Level::Error),
             Position(self.timecode),
             Input(self.origin.to_string_lossy().to_string()),
             Frames(1),
-            Scale(Dim::W(640)),
-            Format(self.encoding.clone()),
-            Output(Destination::Stdout),
-        ]
-        .into_iter()
-        .flat_map(|o| o.process_option())
-        .collect()
+        ];
+        if let Some(filter) = self.render.filter() {
+            options.push(Named("-vf".into(), filter));
+        }
+        if let Some(codec) = self.render.format.image_codec() {
+            options.push(VideoCodec(codec.into()));
+        }
+        options.extend(self.render.encoder_options());
+        options.push(Format(self.render.format.clone()));
+        options.push(Output(match &self.scratch {
+            Some(path) => Destination::Path(path.clone()),
+            None => Destination::Stdout,
+        }));
+
+        options
+            .into_iter()
+            .flat_map(|o| o.process_option())
+            .collect()
     }
 }
 
 impl Frame {
-    pub fn new<I: Into<PathBuf>>(input: I, timecode: usize) -> Result<Self, ErrorKind> {
+    pub fn new<I: Into<PathBuf>>(
+        input: I,
+        timecode: usize,
+        render: Render,
+    ) -> Result<Self, ErrorKind> {
         let path = input.into();
         if path.exists() {
+            let scratch = render.format.needs_seekable_output().then(|| {
+                env::temp_dir().join(format!(
+                    "frm-frame-{}-{}.{}",
+                    std::process::id(),
+                    SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed),
+                    render.format.extension()
+                ))
+            });
             Ok(Frame {
                 timecode: timecode.max(1),
                 data: None,
                 origin: path,
-                encoding: FormatKind::Png,
+                render,
+                scratch,
             })
         } else {
             Err(ErrorKind::Io(io::ErrorKind::NotFound.into()))
@@ -75,7 +273,16 @@ impl Frame {
             return Ok(());
         }
 
-        let data = self.execute()?.stdout;
+        let data = match &self.scratch {
+            Some(path) => {
+                let output = self.execute();
+                let data = fs::read(path);
+                let _ = fs::remove_file(path);
+                output?;
+                data?
+            }
+            None => self.execute()?.stdout,
+        };
         self.data = Some(ImageData::new(data));
         Ok(())
     }
@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use tracing::info;
 
 use super::cmd::*;
+use super::frame::Render;
 use super::path::existing_path;
 use super::ErrorKind;
 
@@ -14,37 +15,123 @@ pub struct FrameRange {
     start: usize,
     n: usize,
     step: usize,
+    render: Render,
 }
 
 #[derive(Debug, Clone)]
 struct FrameAt {
     timecode: usize,
     origin: PathBuf,
-    encoding: FormatKind,
+    render: Render,
     cache_root: PathBuf,
 }
 
 impl FrameAt {
-    fn cache_path(&self) -> Destination {
-        Destination::Path(self.cache_root.join(format!("{}.png", self.timecode)))
+    fn cache_path(&self) -> PathBuf {
+        self.cache_root.join(format!(
+            "{}.{}",
+            self.timecode,
+            self.render.format.extension()
+        ))
     }
-}
 
-impl From<FrameAt> for Vec<String> {
-    fn from(f: FrameAt) -> Self {
+    fn input(&self) -> Vec<CommandOption> {
         use CommandOption::*;
-        let output = f.cache_path();
         vec![
-            Position(f.timecode),
-            Input(f.origin.to_string_lossy().to_string()),
-            Frames(1),
-            Scale(Dim::W(640)),
-            Format(f.encoding),
-            Output(output),
+            Position(self.timecode),
+            Input(self.origin.to_string_lossy().to_string()),
         ]
-        .into_iter()
-        .flat_map(|o| o.process_option())
-        .collect()
+    }
+
+    /// Writes the first frame of input number `input` to the cache.
+    fn output(&self, input: usize) -> Vec<CommandOption> {
+        use CommandOption::*;
+        let mut options = vec![Named("-map".into(), format!("{input}:v:0")), Frames(1)];
+        if let Some(filter) = self.render.filter() {
+            options.push(Named("-vf".into(), filter));
+        }
+        if let Some(codec) = self.render.format.image_codec() {
+            options.push(VideoCodec(codec.into()));
+        }
+        options.extend(self.render.encoder_options());
+        options.push(Output(Destination::Path(self.cache_path())));
+        options
+    }
+}
+
+/// Frames at arbitrary timecodes, each seeked to on its own like a single
+/// frame would be, but all rendered by one ffmpeg run.
+#[derive(Debug, Clone)]
+pub struct FrameSet {
+    frames: Vec<FrameAt>,
+}
+
+impl Command for FrameSet {
+    #[tracing::instrument(skip_all)]
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+
+        // every input has to come before the outputs that map it
+        let inputs = self.frames.iter().flat_map(FrameAt::input);
+        let outputs = self
+            .frames
+            .iter()
+            .enumerate()
+            .flat_map(|(i, f)| f.output(i));
+
+        std::iter::once(LogLevel(Level::Error))
+            .chain(inputs)
+            .chain(outputs)
+            .flat_map(|o| o.process_option())
+            .collect()
+    }
+}
+
+impl FrameSet {
+    pub fn new(
+        origin: &str,
+        cache_root: &str,
+        timecodes: &[usize],
+        render: Render,
+    ) -> Result<Self, ErrorKind> {
+        let origin = existing_path(origin)?.to_path_buf();
+        let cache_root = existing_path(cache_root)?.to_path_buf();
+        Ok(FrameSet {
+            frames: timecodes
+                .iter()
+                .map(|&timecode| FrameAt {
+                    timecode,
+                    origin: origin.clone(),
+                    render: render.clone(),
+                    cache_root: cache_root.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    /// Renders every frame into the cache, returning their timecodes.
+    #[tracing::instrument(skip_all)]
+    pub fn read(&self) -> Result<Vec<usize>, ErrorKind> {
+        match self.execute()?.status.code() {
+            Some(0) => {}
+            Some(code) => return Err(ErrorKind::Unhandled(format!("Non-zero exit code {code}"))),
+            None => return Err(ErrorKind::Unhandled("Failed without exit code".into())),
+        };
+
+        self.frames
+            .iter()
+            .map(|f| {
+                let pb = f.cache_path();
+                if pb.exists() {
+                    Ok(f.timecode)
+                } else {
+                    Err(ErrorKind::Unhandled(format!(
+                        "expected all set files to exist, missing {}",
+                        pb.to_string_lossy()
+                    )))
+                }
+            })
+            .collect()
     }
 }
 
@@ -66,7 +153,7 @@ impl Command for FrameRange {
         };
         info!("string fps: {fps}");
 
-        let out: Vec<_> = vec![
+        let mut options = vec![
             LogLevel(Level::Error),
             Position(self.start),
             Duration(total_duration),
@@ -74,12 +161,27 @@ impl Command for FrameRange {
             // Frames(1),
             // Scale(Dim::W(640)),
             // Format(self.encoding.clone()),
-            Named("-vf".into(), format!("fps={fps},scale=640:-1")),
-            Output(Destination::Path(self.cache_root.join("%04d.png"))),
-        ]
-        .into_iter()
-        .flat_map(|o| o.process_option())
-        .collect();
+            Named(
+                "-vf".into(),
+                match self.render.filter() {
+                    Some(filter) => format!("fps={fps},{filter}"),
+                    None => format!("fps={fps}"),
+                },
+            ),
+        ];
+        if let Some(codec) = self.render.format.image_codec() {
+            options.push(VideoCodec(codec.into()));
+        }
+        options.extend(self.render.encoder_options());
+        options.push(Output(Destination::Path(
+            self.cache_root
+                .join(format!("%04d.{}", self.render.format.extension())),
+        )));
+
+        let out: Vec<_> = options
+            .into_iter()
+            .flat_map(|o| o.process_option())
+            .collect();
 
         info!("{}", out.join(" "));
 
@@ -94,6 +196,7 @@ impl FrameRange {
         start: usize,
         n: usize,
         step: usize,
+        render: Render,
     ) -> Result<Self, ErrorKind> {
         Ok(FrameRange {
             origin: existing_path(origin)?.to_path_buf(),
@@ -101,6 +204,7 @@ impl FrameRange {
             start,
             n,
             step,
+            render,
         })
     }
 
@@ -113,9 +217,10 @@ impl FrameRange {
             None => return Err(ErrorKind::Unhandled("Failed without exit code".into())),
         };
 
+        let extension = self.render.format.extension();
         for n in 0..self.n - 1 {
             let timecode = self.start + n * self.step;
-            let pb = self.cache_root.join(format!("{:04}.png", n + 1));
+            let pb = self.cache_root.join(format!("{:04}.{extension}", n + 1));
             if !pb.exists() {
                 return Err(ErrorKind::Unhandled(format!(
                     "expected all range files to exist, missing {}",
@@ -128,7 +233,7 @@ impl FrameRange {
                 pb.parent()
                     .expect("path is neither blank nor /")
                     .to_path_buf()
-                    .join(format!("{timecode}.png")),
+                    .join(format!("{timecode}.{extension}")),
             )?;
 
             output.push(timecode);
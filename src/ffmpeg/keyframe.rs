@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::{keyframes, ErrorKind};
+
+/// How a requested timecode maps onto the frame that's served.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Snap {
+    /// The frame at the timecode, decoded from the keyframe before it
+    #[default]
+    Exact,
+
+    /// The keyframe at or before the timecode, which needs no extra decoding
+    Keyframe,
+
+    /// Whichever keyframe is closest
+    Nearest,
+}
+
+/// Every video keyframe in a file, in ms.
+#[derive(Debug, Clone)]
+pub struct KeyframeIndex {
+    times: Vec<usize>,
+}
+
+impl KeyframeIndex {
+    /// `start_time` is the file's, which packet timestamps are offset by.
+    #[tracing::instrument]
+    pub fn probe(path_str: &str, start_time: Duration) -> Result<Self, ErrorKind> {
+        Ok(Self::new(&keyframes(path_str, None)?, start_time))
+    }
+
+    pub fn new(keyframes: &[Duration], start_time: Duration) -> Self {
+        // rounded up, since seeking to a time just before a keyframe starts
+        // decoding from the keyframe before that
+        let times = keyframes
+            .iter()
+            .map(|k| {
+                let nanos = k.saturating_sub(start_time).as_nanos();
+                nanos.div_ceil(1_000_000) as usize
+            })
+            .collect();
+        KeyframeIndex { times }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    pub fn snap(&self, at: usize, snap: Snap) -> usize {
+        if self.is_empty() {
+            return at;
+        }
+
+        // index of the first keyframe after `at`
+        let after = self.times.partition_point(|t| *t <= at);
+        let before = after.checked_sub(1).map(|i| self.times[i]);
+        let next = self.times.get(after).copied();
+
+        match (snap, before, next) {
+            (Snap::Exact, _, _) => at,
+            (Snap::Keyframe, Some(b), _) => b,
+            (Snap::Nearest, Some(b), Some(n)) if n - at < at - b => n,
+            (Snap::Nearest, Some(b), _) => b,
+            (_, None, Some(n)) => n,
+            (_, None, None) => at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snaps_onto_keyframes() {
+        // 24000/1001 fps with a keyframe every 25 frames, offset like a
+        // transport stream
+        let start = Duration::from_secs_f64(1.4);
+        let keyframes: Vec<_> = (0..4)
+            .map(|i| start + Duration::from_secs_f64(i as f64 * 25.0 * 1001.0 / 24000.0))
+            .collect();
+        let index = KeyframeIndex::new(&keyframes, start);
+
+        for (at, snap) in [(1100, Snap::Keyframe), (2000, Snap::Nearest)] {
+            let snapped = Duration::from_millis(index.snap(at, snap) as u64);
+            let keyframe = keyframes
+                .iter()
+                .rev()
+                .map(|k| *k - start)
+                .find(|k| *k <= snapped)
+                .expect("snapped before the first keyframe");
+            // seeking there lands on the keyframe rather than starting a GOP
+            // early or skipping the frame after it
+            assert!(snapped - keyframe < Duration::from_millis(1));
+        }
+
+        assert_eq!(index.snap(1100, Snap::Keyframe), 1043);
+        assert_eq!(index.snap(2000, Snap::Nearest), 2086);
+        assert_eq!(index.snap(500, Snap::Keyframe), 0);
+        assert_eq!(index.snap(500, Snap::Exact), 500);
+    }
+}
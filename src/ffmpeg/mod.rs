@@ -1,40 +1,133 @@
+pub mod breaks;
+pub mod chapter;
 pub mod cmd;
+pub mod cut;
 mod error;
 // mod filter;
 pub mod frame;
 pub mod frame_range;
+pub mod keyframe;
 mod path;
+pub mod probe;
 pub mod proc;
+pub mod scene;
 pub mod sequence;
+pub mod sheet;
 pub mod subtitle;
 
 use std::time::Duration;
 
 pub use error::ErrorKind;
+use probe::Probe;
+use subtitle::SubtitleStream;
 
-pub fn duration(path_str: &str) -> Result<Duration, ErrorKind> {
+/// Format, streams and chapters of the file, in one ffprobe run.
+pub fn probe(path_str: &str) -> Result<Probe, ErrorKind> {
     let path = path::existing_path(path_str)?;
 
     let args = vec![
         "-v",
         "error",
+        "-show_format",
+        "-show_streams",
+        "-show_chapters",
+        "-of",
+        "json",
+        path.to_str().unwrap(),
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+
+    let output = proc::dump(proc::run("ffprobe", args)?)?;
+    Probe::parse(&output).map_err(ErrorKind::from)
+}
+
+/// All subtitle streams in the file, in the order `-map 0:s:N` addresses them.
+pub fn subtitle_streams(path_str: &str) -> Result<Vec<SubtitleStream>, ErrorKind> {
+    Ok(probe(path_str)?.subtitle_streams())
+}
+
+/// Codec and pixel format of the stream matching `selector`, e.g. `v:0`.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub codec: String,
+    pub pix_fmt: Option<String>,
+}
+
+pub fn stream_info(path_str: &str, selector: &str) -> Result<Option<StreamInfo>, ErrorKind> {
+    let path = path::existing_path(path_str)?;
+
+    let args = vec![
+        "-v",
+        "error",
+        "-select_streams",
+        selector,
         "-show_entries",
-        "format=duration",
+        "stream=codec_name,pix_fmt",
         "-of",
-        "default=noprint_wrappers=1:nokey=1",
+        "json",
         path.to_str().unwrap(),
     ]
     .into_iter()
     .map(String::from)
     .collect::<Vec<_>>();
 
-    let output = proc::run("ffprobe", args)?;
-    match proc::parse_from_output::<f32>(output) {
-        Ok(secs) if secs > 0_f32 => Ok(Duration::from_millis((secs * 1000.0).floor() as u64)),
-        Ok(_) => Err(ErrorKind::Unhandled(
-            "Weirdly small/negative duration".into(),
-        )),
+    let output = proc::dump(proc::run("ffprobe", args)?)?;
+    let probe: serde_json::Value = serde_json::from_str(&output)
+        .map_err(|e| proc::OutputError::Parse(format!("invalid ffprobe output: {e}")))?;
 
-        Err(e) => Err(ErrorKind::Output(e)),
+    Ok(probe["streams"].get(0).and_then(|s| {
+        Some(StreamInfo {
+            codec: s["codec_name"].as_str()?.to_string(),
+            pix_fmt: s["pix_fmt"].as_str().map(String::from),
+        })
+    }))
+}
+
+/// Timestamps of the video keyframes in and around `range` (ms), or in the
+/// whole file, read from packet flags so nothing has to be decoded. Both are
+/// the packets' own, not shifted by the file's start time.
+pub fn keyframes(
+    path_str: &str,
+    range: Option<(usize, usize)>,
+) -> Result<Vec<Duration>, ErrorKind> {
+    let path = path::existing_path(path_str)?;
+
+    let mut args = vec!["-v", "error", "-select_streams", "v:0"]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if let Some((from, to)) = range {
+        args.push("-read_intervals".into());
+        args.push(format!(
+            "{:.3}%{:.3}",
+            from as f64 / 1000.0,
+            to as f64 / 1000.0
+        ));
     }
+    args.extend(
+        vec![
+            "-show_entries",
+            "packet=pts_time,flags",
+            "-of",
+            "csv=p=0",
+            path.to_str().unwrap(),
+        ]
+        .into_iter()
+        .map(String::from),
+    );
+
+    let output = proc::dump(proc::run("ffprobe", args)?)?;
+    let mut keyframes: Vec<Duration> = output
+        .lines()
+        .filter_map(|l| l.trim().split_once(','))
+        .filter(|(_, flags)| flags.starts_with('K'))
+        .filter_map(|(pts, _)| pts.parse::<f64>().ok())
+        .filter(|secs| *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .collect();
+    keyframes.sort();
+    keyframes.dedup();
+    Ok(keyframes)
 }
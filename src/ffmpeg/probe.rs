@@ -0,0 +1,210 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Deserialize;
+
+use super::chapter::Chapter;
+use super::proc::OutputError;
+use super::subtitle::SubtitleStream;
+
+/// Everything `ffprobe -show_format -show_streams -show_chapters` reports
+/// that we use. ffprobe prints most numbers as strings, so those are kept
+/// as-is and parsed by the accessors.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+    #[serde(default)]
+    pub chapters: Vec<RawChapter>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Format {
+    #[serde(default)]
+    pub format_name: String,
+    pub start_time: Option<String>,
+    pub duration: Option<String>,
+    pub bit_rate: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Stream {
+    pub index: usize,
+    #[serde(default)]
+    pub codec_type: String,
+    #[serde(default)]
+    pub codec_name: String,
+    pub profile: Option<String>,
+
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pix_fmt: Option<String>,
+    pub sample_aspect_ratio: Option<String>,
+    pub display_aspect_ratio: Option<String>,
+    pub r_frame_rate: Option<String>,
+    pub avg_frame_rate: Option<String>,
+
+    pub sample_rate: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+
+    #[serde(default)]
+    pub disposition: Disposition,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Disposition {
+    #[serde(default)]
+    pub default: u8,
+    #[serde(default)]
+    pub forced: u8,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RawChapter {
+    pub start_time: String,
+    pub end_time: String,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+fn secs(s: &str) -> Option<Duration> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Parses ffprobe's `num/den` and `num:den` ratios, which are `0/0` or
+/// `0:1` when unknown.
+fn ratio(s: &str, sep: char) -> Option<f64> {
+    let (num, den) = s.split_once(sep)?;
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+impl Probe {
+    pub fn parse(input: &str) -> Result<Self, OutputError> {
+        serde_json::from_str(input)
+            .map_err(|e| OutputError::Parse(format!("invalid ffprobe output: {e}")))
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.format.duration.as_deref().and_then(secs)
+    }
+
+    pub fn start_time(&self) -> Option<Duration> {
+        self.format.start_time.as_deref().and_then(secs)
+    }
+
+    pub fn bit_rate(&self) -> Option<u64> {
+        self.format.bit_rate.as_deref().and_then(|r| r.parse().ok())
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.format.tags.get("title").map(String::as_str)
+    }
+
+    fn of_type<'a>(&'a self, codec_type: &'a str) -> impl Iterator<Item = &'a Stream> {
+        self.streams
+            .iter()
+            .filter(move |s| s.codec_type == codec_type)
+    }
+
+    /// The stream `-map 0:v:0` picks.
+    pub fn video(&self) -> Option<&Stream> {
+        self.of_type("video").next()
+    }
+
+    pub fn audio(&self) -> Vec<&Stream> {
+        self.of_type("audio").collect()
+    }
+
+    /// Subtitle streams in the order `-map 0:s:N` addresses them.
+    pub fn subtitle_streams(&self) -> Vec<SubtitleStream> {
+        self.of_type("subtitle")
+            .enumerate()
+            .map(|(index, s)| SubtitleStream {
+                index,
+                stream_index: s.index,
+                codec: s.codec_name.clone(),
+                language: s.language().map(String::from),
+                title: s.title().map(String::from),
+                default: s.is_default(),
+                forced: s.disposition.forced != 0,
+            })
+            .collect()
+    }
+
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.chapters
+            .iter()
+            .enumerate()
+            .filter_map(|(id, c)| {
+                Some(Chapter {
+                    id,
+                    start: secs(&c.start_time)?.as_millis() as usize,
+                    end: secs(&c.end_time)?.as_millis() as usize,
+                    title: c.tags.get("title").cloned(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Stream {
+    pub fn language(&self) -> Option<&str> {
+        self.tags.get("language").map(String::as_str)
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.tags.get("title").map(String::as_str)
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.disposition.default != 0
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate.as_deref().and_then(|r| r.parse().ok())
+    }
+
+    /// Frames per second, preferring the average rate over the container's
+    /// base rate, which is often a multiple of it.
+    pub fn frame_rate(&self) -> Option<f64> {
+        self.avg_frame_rate
+            .as_deref()
+            .and_then(|r| ratio(r, '/'))
+            .or_else(|| self.r_frame_rate.as_deref().and_then(|r| ratio(r, '/')))
+    }
+
+    /// Width over height as displayed, accounting for non-square pixels.
+    pub fn display_aspect_ratio(&self) -> Option<f64> {
+        if let Some(dar) = self
+            .display_aspect_ratio
+            .as_deref()
+            .and_then(|r| ratio(r, ':'))
+        {
+            return Some(dar);
+        }
+        let (w, h) = (self.width? as f64, self.height? as f64);
+        if w <= 0.0 || h <= 0.0 {
+            return None;
+        }
+        let sar = self
+            .sample_aspect_ratio
+            .as_deref()
+            .and_then(|r| ratio(r, ':'))
+            .unwrap_or(1.0);
+        Some(w * sar / h)
+    }
+}
@@ -1,9 +1,11 @@
 use std::io;
 use std::path::Path;
-use std::process::{Command, Output};
-use std::str::{from_utf8, FromStr, Utf8Error};
+use std::process::{Command, Output, Stdio};
+use std::str::{from_utf8, Utf8Error};
 
+use futures::Stream;
 use thiserror::Error;
+use tokio::io::AsyncReadExt;
 
 #[derive(Debug, Error)]
 pub enum OutputError {
@@ -40,11 +42,70 @@ pub fn run(name: &str, args: Vec<String>) -> Result<Output, OutputError> {
     }
 }
 
+/// Runs `name` and yields its stdout as it's produced. A non-zero exit is
+/// yielded as a final error, so a truncated stream isn't taken for success.
+/// Stderr is collected alongside, since a full pipe would stall the process.
+#[tracing::instrument]
+pub fn stream(
+    name: &str,
+    args: Vec<String>,
+) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, OutputError> {
+    let mut child = tokio::process::Command::new(name)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr = tokio::spawn(async move {
+        let mut text = Vec::new();
+        stderr.read_to_end(&mut text).await.map(|_| text)
+    });
+    let name = name.to_string();
+
+    Ok(futures::stream::unfold(
+        Some((child, stdout, stderr)),
+        move |state| {
+            let name = name.clone();
+            async move {
+                let (mut child, mut stdout, stderr) = state?;
+                let mut buf = vec![0; 64 * 1024];
+                match stdout.read(&mut buf).await {
+                    Ok(0) => match child.wait().await {
+                        Ok(status) if status.success() => None,
+                        Ok(_) => {
+                            let text = match stderr.await {
+                                Ok(Ok(text)) => String::from_utf8_lossy(&text).trim().to_string(),
+                                _ => String::new(),
+                            };
+                            let message = format!("{name} failed: {text}");
+                            Some((Err(io::Error::other(message)), None))
+                        }
+                        Err(e) => Some((Err(e), None)),
+                    },
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(buf), Some((child, stdout, stderr))))
+                    }
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        },
+    ))
+}
+
 pub fn dump(output: Output) -> Result<String, OutputError> {
     let text = from_utf8(output.stdout.as_slice())?;
     Ok(String::from(text))
 }
 
+/// ffmpeg reports filter output like `showinfo` on stderr.
+pub fn dump_stderr(output: Output) -> Result<String, OutputError> {
+    let text = from_utf8(output.stderr.as_slice())?;
+    Ok(String::from(text))
+}
+
 pub fn write_to_file(path: &Path, output: &Vec<u8>) -> Result<(), OutputError> {
     use std::fs::File;
     use std::io::Write;
@@ -59,19 +120,35 @@ pub fn write_to_file(path: &Path, output: &Vec<u8>) -> Result<(), OutputError> {
     file.write_all(bytes).map_err(OutputError::from)
 }
 
-pub fn parse_from_output<T>(output: Output) -> Result<T, OutputError>
-where
-    T: FromStr,
-    T::Err: std::fmt::Display,
-{
-    let resolved_output = dump(output)?;
-    let text = resolved_output.as_str();
-
-    match text.trim().parse::<T>() {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            let message = format!("{e} ({text})");
-            Err(OutputError::Parse(message))
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{StreamExt, TryStreamExt};
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".into(), script.into()]
+    }
+
+    #[tokio::test]
+    async fn stream_survives_chatty_stderr() {
+        // well past a pipe's buffer, written before any stdout
+        let script = "head -c 1000000 /dev/zero >&2; printf done";
+        let chunks: Vec<Vec<u8>> = stream("sh", sh(script))
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"done");
+    }
+
+    #[tokio::test]
+    async fn stream_reports_failure_with_stderr() {
+        let chunks: Vec<_> = stream("sh", sh("printf out; echo broken >&2; exit 3"))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks[0].as_ref().unwrap(), b"out");
+        let err = chunks[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("broken"), "{}", err);
     }
 }
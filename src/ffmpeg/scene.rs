@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::cmd::*;
+use super::path::existing_path;
+use super::proc;
+use super::ErrorKind;
+
+/// Frames are compared at this width; scene scores barely change with size
+/// and decoding is the bottleneck.
+const ANALYSIS_WIDTH: u32 = 320;
+
+/// The first frame of a new shot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneChange {
+    pub timecode: usize,
+
+    /// How different the frame is from the one before it, from 0 to 1
+    pub score: f32,
+}
+
+/// Finds shot boundaries across a whole file with ffmpeg's scene score.
+#[derive(Debug, Clone)]
+pub struct SceneDetect {
+    origin: PathBuf,
+    threshold: f32,
+    start_time: Duration,
+}
+
+impl SceneDetect {
+    /// `start_time` is the file's, which frame timestamps are offset by.
+    pub fn new(origin: &str, threshold: f32, start_time: Duration) -> Result<Self, ErrorKind> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(ErrorKind::ArgumentError);
+        }
+        Ok(SceneDetect {
+            origin: existing_path(origin)?.to_path_buf(),
+            threshold,
+            start_time,
+        })
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn read(&self) -> Result<Vec<SceneChange>, ErrorKind> {
+        let log = proc::dump_stderr(self.execute()?)?;
+        Ok(parse_scene_log(&log, self.start_time))
+    }
+}
+
+impl Command for SceneDetect {
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+        vec![
+            LogLevel(Level::Info),
+            Positional("-nostats".into()),
+            Input(self.origin.to_string_lossy().to_string()),
+            Positional("-an".into()),
+            Positional("-sn".into()),
+            Named(
+                "-vf".into(),
+                format!(
+                    "scale={ANALYSIS_WIDTH}:-2,select='gt(scene,{})',metadata=print",
+                    self.threshold
+                ),
+            ),
+            Format(FormatKind::Null),
+            Output(Destination::Stdout),
+        ]
+        .into_iter()
+        .flat_map(CommandOption::process_option)
+        .collect()
+    }
+}
+
+// [Parsed_metadata_2 @ 0x5581] frame:0    pts:120120  pts_time:5.005
+// [Parsed_metadata_2 @ 0x5581] lavfi.scene_score=0.412093
+
+/// Pairs each `metadata=print` frame line with the scene score after it.
+/// Timestamps are shifted back by `start_time` so they line up with seeks.
+pub fn parse_scene_log(log: &str, start_time: Duration) -> Vec<SceneChange> {
+    let start = start_time.as_secs_f64();
+    let mut changes = Vec::new();
+    let mut pts_time: Option<f64> = None;
+
+    for line in log.lines() {
+        if let Some((_, rest)) = line.split_once("pts_time:") {
+            pts_time = rest.split_whitespace().next().and_then(|t| t.parse().ok());
+        } else if let Some((_, score)) = line.split_once("lavfi.scene_score=") {
+            if let (Some(secs), Ok(score)) = (pts_time.take(), score.trim().parse::<f32>()) {
+                changes.push(SceneChange {
+                    timecode: ((secs - start).max(0.0) * 1000.0).round() as usize,
+                    score,
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+/// Splits `from..to` at each scene change, then merges the shortest shot
+/// into its shorter neighbour until at most `n` remain. Returns the midpoint
+/// of each shot.
+pub fn representative_frames(
+    changes: &[SceneChange],
+    from: usize,
+    to: usize,
+    n: usize,
+) -> Vec<usize> {
+    let mut bounds = vec![from];
+    bounds.extend(
+        changes
+            .iter()
+            .map(|c| c.timecode)
+            .filter(|t| *t > from && *t < to),
+    );
+    bounds.push(to);
+
+    let mut shots: Vec<(usize, usize)> = bounds.windows(2).map(|w| (w[0], w[1])).collect();
+    let len = |(start, end): (usize, usize)| end - start;
+
+    while shots.len() > n.max(1) {
+        let (shortest, _) = shots
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| len(**s))
+            .expect("more than one shot");
+        let neighbour = match shortest {
+            0 => 1,
+            i if i == shots.len() - 1 => i - 1,
+            i if len(shots[i - 1]) <= len(shots[i + 1]) => i - 1,
+            i => i + 1,
+        };
+        let (a, b) = (shortest.min(neighbour), shortest.max(neighbour));
+        shots[a] = (shots[a].0, shots[b].1);
+        shots.remove(b);
+    }
+
+    shots
+        .into_iter()
+        .map(|(start, end)| start + (end - start) / 2)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_times_are_relative_to_the_start() {
+        let log = "\
+[Parsed_metadata_2 @ 0x5581] frame:0    pts:120120  pts_time:6.405
+[Parsed_metadata_2 @ 0x5581] lavfi.scene_score=0.412093
+[Parsed_metadata_2 @ 0x5581] frame:1    pts:240240  pts_time:nope
+[Parsed_metadata_2 @ 0x5581] lavfi.scene_score=0.5
+";
+        let changes = parse_scene_log(log, Duration::from_millis(1400));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].timecode, 5005);
+        assert!((changes[0].score - 0.412093).abs() < 1e-6);
+    }
+
+    fn changes(timecodes: &[usize]) -> Vec<SceneChange> {
+        timecodes
+            .iter()
+            .map(|&timecode| SceneChange {
+                timecode,
+                score: 0.5,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn one_frame_per_shot_in_range() {
+        let changes = changes(&[0, 1000, 4000, 9000, 20_000]);
+        assert_eq!(
+            representative_frames(&changes, 0, 10_000, 10),
+            vec![500, 2500, 6500, 9500]
+        );
+        assert_eq!(representative_frames(&[], 2000, 4000, 3), vec![3000]);
+    }
+
+    #[test]
+    fn merges_shortest_shots_into_shorter_neighbours() {
+        // shots of 1000, 3000, 5000 and 1000 ms
+        let changes = changes(&[1000, 4000, 9000]);
+        assert_eq!(
+            representative_frames(&changes, 0, 10_000, 3),
+            vec![2000, 6500, 9500]
+        );
+        assert_eq!(
+            representative_frames(&changes, 0, 10_000, 2),
+            vec![2000, 7000]
+        );
+        assert_eq!(representative_frames(&changes, 0, 10_000, 0), vec![5000]);
+    }
+}
@@ -1,6 +1,19 @@
+use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use super::cmd::{Command, CommandOption};
+use futures::Stream;
+use serde::Deserialize;
+
+use super::cmd::{Command, CommandOption, FormatKind};
+use super::cut::SmartCut;
+use super::proc;
+use super::subtitle::{self, Subtitle, SubtitleStream};
+use super::ErrorKind;
+
+/// Layout of raw PCM output: interleaved little-endian `i16` samples.
+pub const PCM_CHANNELS: u16 = 2;
+pub const PCM_SAMPLE_RATE: u32 = 48_000;
 
 #[derive(Debug, Clone)]
 pub enum OutputKind {
@@ -13,41 +26,268 @@ pub enum OutputKind {
 pub struct Sequence {
     path: PathBuf,
     output: OutputKind,
+    format: FormatKind,
+    stream: Option<SubtitleStream>,
+    quality: Quality,
+    width: Option<u32>,
+    audio_stream: usize,
     start: usize,
     end: usize,
 }
 
+/// Encoder presets for clip export, trading speed and size for fidelity.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+impl Quality {
+    /// x264 preset, x264 CRF and AAC bitrate
+    fn encoder_settings(self) -> (&'static str, u8, &'static str) {
+        match self {
+            Quality::Low => ("veryfast", 28, "96k"),
+            Quality::Medium => ("fast", 23, "128k"),
+            Quality::High => ("slow", 18, "192k"),
+        }
+    }
+}
+
+fn format_for_codec(codec: &str) -> Result<FormatKind, ErrorKind> {
+    match codec {
+        "ass" | "ssa" => Ok(FormatKind::Ass),
+        // bitmap tracks would need OCR to become text
+        "hdmv_pgs_subtitle" | "dvd_subtitle" | "dvb_subtitle" | "dvb_teletext" | "xsub" => {
+            Err(ErrorKind::UnsupportedSubtitles(codec.to_string()))
+        }
+        _ => Ok(FormatKind::Srt),
+    }
+}
+
 impl Sequence {
     pub fn subtitles<P: Into<PathBuf>>(path: P, start: usize, end: usize) -> Self {
         Self {
             path: path.into(),
             output: OutputKind::Text,
+            format: FormatKind::Srt,
+            stream: None,
+            quality: Quality::default(),
+            width: None,
+            audio_stream: 0,
             start,
             end,
         }
     }
+
+    /// The range's audio, as Opus in Ogg unless another format is set.
+    pub fn audio<P: Into<PathBuf>>(path: P, start: usize, end: usize) -> Self {
+        Self {
+            output: OutputKind::Audio,
+            format: FormatKind::Ogg,
+            ..Self::subtitles(path, start, end)
+        }
+    }
+
+    /// Reads from the `n`th audio stream instead of the first one.
+    pub fn with_audio_stream(self, n: usize) -> Self {
+        Self {
+            audio_stream: n,
+            ..self
+        }
+    }
+
+    /// An H.264/AAC MP4 of the range, fragmented so it can be written to a
+    /// pipe.
+    pub fn video<P: Into<PathBuf>>(path: P, start: usize, end: usize) -> Self {
+        Self {
+            output: OutputKind::Video,
+            format: FormatKind::Mp4,
+            ..Self::subtitles(path, start, end)
+        }
+    }
+
+    pub fn with_quality(self, quality: Quality) -> Self {
+        Self { quality, ..self }
+    }
+
+    /// Scales the output to `width`, keeping the aspect ratio. Odd widths
+    /// are rounded down, since H.264 needs even dimensions.
+    pub fn with_width(self, width: Option<u32>) -> Self {
+        Self {
+            width: width.map(|w| (w / 2 * 2).max(2)),
+            ..self
+        }
+    }
+
+    /// Prepares a frame-accurate cut of the range that copies rather than
+    /// re-encodes wherever keyframes allow, using `workdir` for the pieces.
+    /// `start_time` is the file's, as probed.
+    pub fn smart_cut(&self, workdir: PathBuf, start_time: Duration) -> Result<SmartCut, ErrorKind> {
+        SmartCut::prepare(&self.path, workdir, self.start, self.end, start_time)
+    }
+
+    /// Runs the command, yielding its output as ffmpeg produces it.
+    pub fn stream(&self) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, ErrorKind> {
+        Ok(proc::stream("ffmpeg", self.build())?)
+    }
+
+    /// Sets the format ffmpeg is asked to write, e.g. WebVTT for consumption
+    /// by a browser `<track>` or WAV rather than Opus for audio.
+    pub fn with_format(self, format: FormatKind) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Reads from the given subtitle stream instead of the first one.
+    pub fn with_stream(self, stream: SubtitleStream) -> Self {
+        Self {
+            stream: Some(stream),
+            ..self
+        }
+    }
+
+    /// Returns ffmpeg's output in full.
+    #[tracing::instrument(skip_all)]
+    pub fn read_bytes(&self) -> Result<Vec<u8>, ErrorKind> {
+        Ok(self.execute()?.stdout)
+    }
+
+    /// Returns ffmpeg's text output as-is. Timings are relative to the start
+    /// of the range, except in WebVTT, where they're relative to the start of
+    /// the file so the output can be loaded in a `<track>` unchanged.
+    #[tracing::instrument(skip_all)]
+    pub fn read_text(&self) -> Result<String, ErrorKind> {
+        Ok(proc::dump(self.execute()?)?)
+    }
+
+    /// Extracts and parses the cues in range, with timings relative to the
+    /// start of the file rather than the start of the range. ASS/SSA tracks
+    /// are read as-is; other text tracks are converted to SRT by ffmpeg.
+    #[tracing::instrument(skip_all)]
+    pub fn read_subtitles(&self) -> Result<Vec<Subtitle>, ErrorKind> {
+        let stream = match &self.stream {
+            Some(stream) => stream.clone(),
+            None => super::subtitle_streams(&self.path.to_string_lossy())?
+                .into_iter()
+                .next()
+                .ok_or_else(|| ErrorKind::UnsupportedSubtitles("no subtitle stream".into()))?,
+        };
+        let format = format_for_codec(&stream.codec)?;
+
+        let text = self
+            .clone()
+            .with_stream(stream)
+            .with_format(format.clone())
+            .read_text()?;
+        let subs = match format {
+            FormatKind::Ass => subtitle::parse_ass(&text)?,
+            _ => subtitle::parse_srt(&text)?,
+        };
+
+        let offset = Duration::from_millis(self.start as u64);
+        Ok(subs.into_iter().map(|s| s.offset(offset)).collect())
+    }
 }
+impl Sequence {
+    fn text_options(&self) -> Vec<CommandOption> {
+        use CommandOption::*;
+
+        let codec = match self.format {
+            FormatKind::Ass => "ass",
+            FormatKind::WebVtt => "webvtt",
+            _ => "srt",
+        };
+
+        let stream = self.stream.as_ref().map_or(0, |s| s.index);
+
+        let mut options = vec![
+            Named("-map".into(), format!("0:s:{stream}")),
+            Named("-c:s".into(), codec.into()),
+        ];
+        // a <track> is timed against the whole video, not the range
+        if self.format == FormatKind::WebVtt {
+            options.push(Named(
+                "-output_ts_offset".into(),
+                format!("{:.3}", (self.start as f64) / 1000.0),
+            ));
+        }
+        options
+    }
+
+    fn video_options(&self) -> Vec<CommandOption> {
+        use CommandOption::*;
+
+        let (preset, crf, audio_bitrate) = self.quality.encoder_settings();
+
+        let mut options = vec![
+            Named("-map".into(), "0:v:0".into()),
+            Named("-map".into(), "0:a:0?".into()),
+            VideoCodec("libx264".into()),
+            Named("-preset".into(), preset.into()),
+            Named("-crf".into(), crf.to_string()),
+            Named("-pix_fmt".into(), "yuv420p".into()),
+            Named("-c:a".into(), "aac".into()),
+            Named("-b:a".into(), audio_bitrate.into()),
+            Named("-movflags".into(), "frag_keyframe+empty_moov".into()),
+        ];
+        if let Some(w) = self.width {
+            // -2 keeps the height even too
+            options.push(Named("-vf".into(), format!("scale={w}:-2")));
+        }
+        options
+    }
+
+    fn audio_options(&self) -> Vec<CommandOption> {
+        use CommandOption::*;
+
+        let mut options = vec![
+            Named("-map".into(), format!("0:a:{}", self.audio_stream)),
+            Positional("-vn".into()),
+            Positional("-sn".into()),
+        ];
+        match self.format {
+            FormatKind::Wav => options.push(Named("-c:a".into(), "pcm_s16le".into())),
+            FormatKind::Pcm => options.extend(vec![
+                Named("-c:a".into(), "pcm_s16le".into()),
+                Named("-ac".into(), PCM_CHANNELS.to_string()),
+                Named("-ar".into(), PCM_SAMPLE_RATE.to_string()),
+            ]),
+            _ => options.extend(vec![
+                Named("-c:a".into(), "libopus".into()),
+                Named("-b:a".into(), "96k".into()),
+                // libopus rejects some surround layouts, like 5.1(side)
+                Named("-ac".into(), "2".into()),
+            ]),
+        }
+        options
+    }
+}
+
 impl Command for Sequence {
     fn build(&self) -> Vec<String> {
         use crate::ffmpeg::cmd::*;
         use CommandOption::*;
 
-        match self.output {
-            OutputKind::Text => {}
-            OutputKind::Video => unimplemented!(),
-            OutputKind::Audio => unimplemented!(),
-        }
+        let output_options = match self.output {
+            OutputKind::Text => self.text_options(),
+            OutputKind::Video => self.video_options(),
+            OutputKind::Audio => self.audio_options(),
+        };
 
         vec![
             LogLevel(Level::Error),
             Position(self.start),
             Input(self.path.to_string_lossy().to_string()),
             Duration(self.end - self.start),
-            Named("-c:s".into(), "copy".into()),
-            Format(FormatKind::Srt),
-            Output(Destination::Stdout),
         ]
         .into_iter()
+        .chain(output_options)
+        .chain(vec![
+            Format(self.format.clone()),
+            Output(Destination::Stdout),
+        ])
         .flat_map(CommandOption::process_option)
         .collect()
     }
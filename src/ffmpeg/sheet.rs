@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use super::cmd::*;
+use super::path::existing_path;
+use super::ErrorKind;
+
+/// Height of the caption bar added under each tile
+const CAPTION_HEIGHT: u32 = 24;
+
+/// A contact sheet: `n` frames sampled evenly from a range and tiled into
+/// one image, left to right and top to bottom.
+#[derive(Debug, Clone)]
+pub struct Sheet {
+    origin: PathBuf,
+    start: usize,
+    step: usize,
+    n: usize,
+    columns: usize,
+    tile_width: u32,
+    tile_height: u32,
+    captions: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Cell {
+    pub index: usize,
+    pub column: usize,
+    pub row: usize,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub timecode: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    pub columns: usize,
+    pub rows: usize,
+    pub cells: Vec<Cell>,
+}
+
+impl Sheet {
+    /// `aspect` is the video's display aspect ratio. Tiles have square
+    /// pixels, so anamorphic video is stretched back to its display shape.
+    pub fn new(
+        origin: &str,
+        start: usize,
+        end: usize,
+        n: usize,
+        tile_width: u32,
+        aspect: f64,
+        captions: bool,
+    ) -> Result<Self, ErrorKind> {
+        if n == 0 || end <= start || (end - start) / n == 0 || tile_width < 2 || aspect <= 0.0 {
+            return Err(ErrorKind::ArgumentError);
+        }
+
+        let origin = existing_path(origin)?.to_path_buf();
+
+        // even dimensions keep every encoder happy
+        let tile_width = tile_width & !1;
+        let tile_height = ((tile_width as f64 / aspect).round() as u32).max(2) & !1;
+
+        Ok(Sheet {
+            origin,
+            start,
+            step: (end - start) / n,
+            n,
+            columns: (n as f64).sqrt().ceil() as usize,
+            tile_width,
+            tile_height,
+            captions,
+        })
+    }
+
+    fn rows(&self) -> usize {
+        self.n.div_ceil(self.columns)
+    }
+
+    fn cell_height(&self) -> u32 {
+        if self.captions {
+            self.tile_height + CAPTION_HEIGHT
+        } else {
+            self.tile_height
+        }
+    }
+
+    /// Where each sampled frame lands in the image, in pixels.
+    pub fn layout(&self) -> Layout {
+        let cell_height = self.cell_height();
+        let cells = (0..self.n)
+            .map(|index| {
+                let (column, row) = (index % self.columns, index / self.columns);
+                Cell {
+                    index,
+                    column,
+                    row,
+                    x: column as u32 * self.tile_width,
+                    y: row as u32 * cell_height,
+                    width: self.tile_width,
+                    height: self.tile_height,
+                    timecode: self.start + index * self.step,
+                }
+            })
+            .collect();
+
+        Layout {
+            width: self.columns as u32 * self.tile_width,
+            height: self.rows() as u32 * cell_height,
+            columns: self.columns,
+            rows: self.rows(),
+            cells,
+        }
+    }
+
+    fn filter(&self) -> String {
+        let mut filters = vec![
+            format!("fps=1000/{}", self.step),
+            format!("scale={}:{}", self.tile_width, self.tile_height),
+            "setsar=1".into(),
+        ];
+        if self.captions {
+            let offset = self.start as f64 / 1000.0;
+            filters.push(format!("pad=iw:ih+{CAPTION_HEIGHT}:0:0:black"));
+            filters.push(format!(
+                "drawtext=text='%{{pts\\:hms\\:{offset:.3}}}':fontcolor=white:fontsize=16:x=(w-tw)/2:y=h-{}",
+                CAPTION_HEIGHT - 4
+            ));
+        }
+        filters.push(format!("tile={}x{}", self.columns, self.rows()));
+        filters.join(",")
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, ErrorKind> {
+        Ok(self.execute()?.stdout)
+    }
+}
+
+impl Command for Sheet {
+    #[tracing::instrument(skip_all)]
+    fn build(&self) -> Vec<String> {
+        use CommandOption::*;
+        vec![
+            LogLevel(Level::Error),
+            Position(self.start),
+            Duration(self.step * self.n),
+            Input(self.origin.to_string_lossy().to_string()),
+            Named("-vf".into(), self.filter()),
+            Frames(1),
+            VideoCodec("png".into()),
+            Format(FormatKind::Png),
+            Output(Destination::Stdout),
+        ]
+        .into_iter()
+        .flat_map(|o| o.process_option())
+        .collect()
+    }
+}
@@ -1,15 +1,19 @@
-use std::{str::FromStr, time::Duration};
+use std::{fmt, str::FromStr, time::Duration};
 
 use nom::{
     bytes::complete::tag,
-    character::complete::{anychar, char, digit1, line_ending, one_of},
-    combinator::map_res,
+    character::complete::{char, digit1, line_ending, multispace0, not_line_ending, one_of},
+    combinator::{all_consuming, map_res, opt, verify},
     error::{FromExternalError, ParseError},
     multi::{count, many0, many1, many_m_n},
     sequence::{preceded, separated_pair, terminated},
     IResult, Parser,
 };
 
+use serde::{Deserialize, Serialize};
+
+use super::proc::OutputError;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Subtitle {
     index: u32,
@@ -23,6 +27,157 @@ pub struct Subtitle {
     content: String,
 }
 
+impl Subtitle {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    pub fn end(&self) -> Duration {
+        self.end
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Shifts the cue by `by`, e.g. the seek position the extraction started at.
+    pub fn offset(self, by: Duration) -> Self {
+        Self {
+            start: self.start + by,
+            end: self.end + by,
+            ..self
+        }
+    }
+}
+
+/// A subtitle track as reported by ffprobe.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubtitleStream {
+    /// Position among the file's subtitle streams, as in `-map 0:s:N`
+    pub index: usize,
+
+    /// Position among all of the file's streams
+    pub stream_index: usize,
+
+    pub codec: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+}
+
+/// ISO 639-1 codes with their ISO 639-2/B equivalents, plus 639-2/T codes
+/// that differ from the /B ones, for languages commonly found in releases.
+const LANGUAGE_CODES: &[(&str, &str)] = &[
+    ("ar", "ara"),
+    ("bg", "bul"),
+    ("bo", "tib"),
+    ("ca", "cat"),
+    ("ces", "cze"),
+    ("cs", "cze"),
+    ("cy", "wel"),
+    ("cym", "wel"),
+    ("da", "dan"),
+    ("de", "ger"),
+    ("deu", "ger"),
+    ("el", "gre"),
+    ("ell", "gre"),
+    ("en", "eng"),
+    ("es", "spa"),
+    ("et", "est"),
+    ("eu", "baq"),
+    ("eus", "baq"),
+    ("fa", "per"),
+    ("fas", "per"),
+    ("fi", "fin"),
+    ("fr", "fre"),
+    ("fra", "fre"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hr", "hrv"),
+    ("hu", "hun"),
+    ("hy", "arm"),
+    ("hye", "arm"),
+    ("id", "ind"),
+    ("is", "ice"),
+    ("isl", "ice"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("ka", "geo"),
+    ("kat", "geo"),
+    ("ko", "kor"),
+    ("lt", "lit"),
+    ("lv", "lav"),
+    ("mk", "mac"),
+    ("mkd", "mac"),
+    ("ms", "may"),
+    ("msa", "may"),
+    ("nl", "dut"),
+    ("nld", "dut"),
+    ("no", "nor"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "rum"),
+    ("ron", "rum"),
+    ("ru", "rus"),
+    ("sk", "slo"),
+    ("sl", "slv"),
+    ("slk", "slo"),
+    ("sq", "alb"),
+    ("sqi", "alb"),
+    ("sr", "srp"),
+    ("sv", "swe"),
+    ("th", "tha"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("vi", "vie"),
+    ("zh", "chi"),
+    ("zho", "chi"),
+];
+
+/// A language tag in the form streams are usually tagged with, ISO 639-2/B,
+/// lowercased. Region subtags, as in `en-US`, are dropped.
+fn normalize_language(tag: &str) -> String {
+    let tag = tag
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match LANGUAGE_CODES.iter().find(|(code, _)| *code == tag) {
+        Some((_, b)) => b.to_string(),
+        None => tag,
+    }
+}
+
+/// Picks the stream requested by index or language tag, falling back to the
+/// one flagged as default and then to the first. Languages match across
+/// two- and three-letter codes, so `en` finds a stream tagged `eng`.
+pub fn select_stream(
+    streams: Vec<SubtitleStream>,
+    index: Option<usize>,
+    lang: Option<&str>,
+) -> Option<SubtitleStream> {
+    match (index, lang) {
+        (Some(n), _) => streams.into_iter().nth(n),
+        (None, Some(lang)) => {
+            let lang = normalize_language(lang);
+            streams.into_iter().find(|s| {
+                s.language
+                    .as_deref()
+                    .is_some_and(|l| normalize_language(l) == lang)
+            })
+        }
+        (None, None) => {
+            let default = streams.iter().position(|s| s.default).unwrap_or(0);
+            streams.into_iter().nth(default)
+        }
+    }
+}
+
 // 1
 // 00:00:00,000 --> 00:00:01,418
 // Shut up!
@@ -55,34 +210,371 @@ fn timecode(input: &str) -> IResult<&str, Duration> {
         (_, None, _) => panic!("impossible"),
     };
 
-    let (i, millis) = preceded(char(';'), digits(3))(i)?;
+    let (i, millis) = preceded(one_of(",."), digits(3))(i)?;
     Ok((
         i,
         Duration::from_secs(seconds) + Duration::from_millis(millis),
     ))
 }
 
+fn text_line(input: &str) -> IResult<&str, &str> {
+    terminated(
+        verify(not_line_ending, |s: &str| !s.trim().is_empty()),
+        opt(line_ending),
+    )(input)
+}
+
 fn parse_subtitle(input: &str) -> IResult<&str, Subtitle> {
     // {index}
     let (i, _) = many0(line_ending)(input)?;
 
-    let (i, index): (&str, u32) = {
-        let (i, idx_s) = digit1(i)?;
-        (i, idx_s.parse().unwrap())
-    };
+    let (i, index): (&str, u32) =
+        terminated(map_res(digit1, |idx: &str| idx.parse()), line_ending)(i)?;
 
-    // {timecode}" --> "{timecode}
-    let (i, (start, end)) = separated_pair(timecode, tag(" --> "), timecode)(i)?;
+    // {timecode}" --> "{timecode}, ignoring any trailing position settings
+    let (i, (start, end)) = terminated(
+        separated_pair(timecode, tag(" --> "), timecode),
+        terminated(not_line_ending, line_ending),
+    )(i)?;
 
-    // {multiline_content}
-    let (i, content) = terminated(many1(anychar), count(line_ending, 2))(i)?;
+    // {multiline_content}, ended by a blank line or the end of input
+    let (i, lines) = many1(text_line)(i)?;
     Ok((
         i,
         Subtitle {
             index,
             start,
             end,
-            content: content.into_iter().collect(),
+            content: lines.join("\n"),
         },
     ))
 }
+
+pub fn parse_srt(input: &str) -> Result<Vec<Subtitle>, OutputError> {
+    let input = input.trim_start_matches('\u{feff}');
+    match all_consuming(terminated(many0(parse_subtitle), multispace0))(input) {
+        Ok((_, subs)) => Ok(subs),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(OutputError::Parse(format!(
+            "invalid srt at byte {}",
+            input.len() - e.input.len()
+        ))),
+        Err(nom::Err::Incomplete(_)) => Err(OutputError::Parse("incomplete srt".into())),
+    }
+}
+
+// [Events]
+// Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+// Dialogue: 0,0:00:01.00,0:00:03.50,Default,,0,0,0,,{\i1}Shut up!{\i0}\Nplease
+//
+
+fn ass_timecode(input: &str) -> IResult<&str, Duration> {
+    let (i, hours): (&str, u64) =
+        terminated(map_res(digit1, |h: &str| h.parse()), char(':'))(input)?;
+    let (i, minutes): (&str, u64) = terminated(digits(2), char(':'))(i)?;
+    let (i, seconds): (&str, u64) = digits(2).parse(i)?;
+    let (i, centis): (&str, u64) = preceded(char('.'), digits(2))(i)?;
+    // hours are unbounded, so a timecode can parse and still not fit
+    let time = hours
+        .checked_mul(60 * 60)
+        .and_then(|secs| secs.checked_add(minutes * 60 + seconds))
+        .and_then(|secs| Duration::from_secs(secs).checked_add(Duration::from_millis(centis * 10)))
+        .ok_or_else(|| {
+            nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TooLarge,
+            ))
+        })?;
+    Ok((i, time))
+}
+
+/// Whether an override block switches drawing mode (`\p1`..) on or off.
+fn ass_drawing_mode(block: &str) -> Option<bool> {
+    block
+        .rsplit('\\')
+        .filter_map(|tag| tag.strip_prefix('p'))
+        .find_map(|scale| scale.trim().parse::<u32>().ok())
+        .map(|scale| scale > 0)
+}
+
+/// Drops `{...}` override blocks and vector drawings, and resolves the
+/// escapes ASS uses for line breaks and hard spaces.
+fn ass_plain_text(raw: &str) -> String {
+    let mut out = String::new();
+    let mut block: Option<String> = None;
+    let mut drawing = false;
+    for c in raw.chars() {
+        match (c, block.as_mut()) {
+            ('{', None) => block = Some(String::new()),
+            ('}', Some(b)) => {
+                drawing = ass_drawing_mode(b).unwrap_or(drawing);
+                block = None;
+            }
+            (_, Some(b)) => b.push(c),
+            (_, None) if drawing => {}
+            (_, None) => out.push(c),
+        }
+    }
+    out.replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", " ")
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn parse_ass(input: &str) -> Result<Vec<Subtitle>, OutputError> {
+    let mut in_events = false;
+    let mut fields: Option<Vec<String>> = None;
+    let mut subs = Vec::new();
+
+    for line in input.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+
+        if let Some(format) = line.strip_prefix("Format:") {
+            fields = Some(
+                format
+                    .split(',')
+                    .map(|f| f.trim().to_ascii_lowercase())
+                    .collect(),
+            );
+        } else if let Some(dialogue) = line.strip_prefix("Dialogue:") {
+            let fields = fields
+                .as_ref()
+                .ok_or_else(|| OutputError::Parse("ass dialogue before format line".into()))?;
+
+            // Text is always the last field and may itself contain commas
+            let values: Vec<&str> = dialogue.trim_start().splitn(fields.len(), ',').collect();
+            let field = |name: &str| {
+                fields
+                    .iter()
+                    .position(|f| f == name)
+                    .and_then(|i| values.get(i))
+                    .ok_or_else(|| OutputError::Parse(format!("ass dialogue missing {name}")))
+            };
+            let time = |name: &str| -> Result<Duration, OutputError> {
+                let raw = field(name)?.trim();
+                all_consuming(ass_timecode)(raw)
+                    .map(|(_, d)| d)
+                    .map_err(|_| OutputError::Parse(format!("invalid ass timecode \"{raw}\"")))
+            };
+
+            let content = ass_plain_text(field("text")?);
+            if content.is_empty() {
+                continue;
+            }
+            subs.push(Subtitle {
+                index: 0,
+                start: time("start")?,
+                end: time("end")?,
+                content,
+            });
+        }
+    }
+
+    subs.sort_by_key(|s| (s.start, s.end));
+    for (n, sub) in subs.iter_mut().enumerate() {
+        sub.index = n as u32 + 1;
+    }
+    Ok(subs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    Start,
+    Center,
+    End,
+}
+
+/// WebVTT cue settings applied to every written cue. Percentages are of the
+/// video viewport, and anything over 100 is written as 100.
+#[derive(Debug, Clone, Default)]
+pub struct CueSettings {
+    pub line: Option<u8>,
+    pub position: Option<u8>,
+    pub size: Option<u8>,
+    pub align: Option<Align>,
+}
+
+impl CueSettings {
+    /// Whether no setting would be written.
+    pub fn is_empty(&self) -> bool {
+        self.line.is_none()
+            && self.position.is_none()
+            && self.size.is_none()
+            && self.align.is_none()
+    }
+}
+
+impl fmt::Display for Align {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Align::Start => write!(f, "start"),
+            Align::Center => write!(f, "center"),
+            Align::End => write!(f, "end"),
+        }
+    }
+}
+
+impl fmt::Display for CueSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(n) = self.line {
+            parts.push(format!("line:{}%", n.min(100)));
+        }
+        if let Some(n) = self.position {
+            parts.push(format!("position:{}%", n.min(100)));
+        }
+        if let Some(n) = self.size {
+            parts.push(format!("size:{}%", n.min(100)));
+        }
+        if let Some(a) = self.align {
+            parts.push(format!("align:{a}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+fn write_timecode(d: Duration, millis_delim: char) -> String {
+    let ms = d.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}{millis_delim}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+pub fn to_srt(subs: &[Subtitle]) -> String {
+    let mut out = String::new();
+    for (n, sub) in subs.iter().enumerate() {
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            n + 1,
+            write_timecode(sub.start, ','),
+            write_timecode(sub.end, ','),
+            sub.content
+        ));
+    }
+    out
+}
+
+/// Escapes cue text, where `&` and `<` start entities and tags, and `-->`
+/// would be read as a new timing line.
+fn escape_vtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub fn to_vtt(subs: &[Subtitle], settings: &CueSettings) -> String {
+    let settings = settings.to_string();
+    let mut out = String::from("WEBVTT\n\n");
+    for sub in subs {
+        out.push_str(&write_timecode(sub.start, '.'));
+        out.push_str(" --> ");
+        out.push_str(&write_timecode(sub.end, '.'));
+        if !settings.is_empty() {
+            out.push(' ');
+            out.push_str(&settings);
+        }
+        out.push('\n');
+        out.push_str(&escape_vtt(&sub.content));
+        out.push_str("\n\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srt_index_overflow_is_an_error() {
+        let srt = "99999999999\n00:00:01,000 --> 00:00:02,000\nHello\n";
+        assert!(parse_srt(srt).is_err());
+    }
+
+    #[test]
+    fn parses_srt_cues() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\nthere\n\n2\n00:00:03,000 --> 00:00:04,000\nBye\n";
+        let subs = parse_srt(srt).unwrap();
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs[0].start, Duration::from_secs(1));
+        assert_eq!(subs[0].end, Duration::from_millis(2500));
+        assert_eq!(subs[0].content, "Hello\nthere");
+    }
+
+    #[test]
+    fn ass_hours_overflow_is_an_error() {
+        assert!(ass_timecode("99999999999999999999:00:01.00").is_err());
+        // fits in a u64, but not once it's in seconds
+        assert!(ass_timecode("9999999999999999:00:00.00").is_err());
+        assert_eq!(
+            ass_timecode("1:02:03.45").unwrap().1,
+            Duration::from_millis(3_723_450)
+        );
+    }
+
+    fn stream(index: usize, language: Option<&str>, default: bool) -> SubtitleStream {
+        SubtitleStream {
+            index,
+            stream_index: index + 2,
+            codec: "subrip".into(),
+            language: language.map(String::from),
+            title: None,
+            default,
+            forced: false,
+        }
+    }
+
+    #[test]
+    fn selects_streams_across_language_codes() {
+        let streams = vec![
+            stream(0, Some("fre"), false),
+            stream(1, Some("eng"), true),
+            stream(2, Some("deu"), false),
+        ];
+        let pick = |lang| select_stream(streams.clone(), None, Some(lang)).map(|s| s.index);
+        assert_eq!(pick("en"), Some(1));
+        assert_eq!(pick("ENG"), Some(1));
+        assert_eq!(pick("en-US"), Some(1));
+        assert_eq!(pick("fra"), Some(0));
+        assert_eq!(pick("de"), Some(2));
+        assert_eq!(pick("ger"), Some(2));
+        assert_eq!(pick("ja"), None);
+        assert_eq!(
+            select_stream(streams.clone(), None, None).map(|s| s.index),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn vtt_escapes_cue_text() {
+        assert_eq!(
+            escape_vtt("Tom & <i>Jerry</i> --> run"),
+            "Tom &amp; &lt;i&gt;Jerry&lt;/i&gt; --&gt; run"
+        );
+    }
+
+    #[test]
+    fn cue_settings_cap_percentages() {
+        let settings = CueSettings {
+            line: Some(250),
+            position: Some(40),
+            size: None,
+            align: Some(Align::End),
+        };
+        assert_eq!(settings.to_string(), "line:100% position:40% align:end");
+    }
+}
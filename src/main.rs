@@ -1,61 +1,325 @@
+mod cache;
+mod config;
 mod ffmpeg;
+mod play;
 mod server;
 // mod span;
 
-use std::{env::current_dir, fs, io};
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use config::{Config, LogFormat};
 
 use ffmpeg::ErrorKind as FfmpegError;
-use tracing::Level;
-use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::{format::FmtSpan, writer::BoxMakeWriter};
 
 type CommandResult<T = ()> = Result<T, FfmpegError>;
 
-async fn handle_serve(matches: &clap::ArgMatches<'_>) -> CommandResult {
-    match matches.value_of("INPUT") {
-        Some(file_path) => {
-            server::FrameServer::new(file_path.to_string())?
-                .serve()
-                .await;
-            Ok(())
-        }
-        None => Err(FfmpegError::ArgumentError),
+/// The config file's settings, overridden by any flags given.
+fn load_config(matches: &clap::ArgMatches<'_>) -> CommandResult<Config> {
+    let config = Config::load(matches.value_of("config").map(Path::new))?;
+    apply_flags(config, matches)
+}
+
+fn apply_flags(mut config: Config, matches: &clap::ArgMatches<'_>) -> CommandResult<Config> {
+    if let Some(dir) = matches.value_of("cache-dir") {
+        config.cache_dir = PathBuf::from(dir);
+    }
+    if let Some(host) = matches.value_of("host") {
+        config.host = host.to_string();
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.port = port.parse().map_err(|_| FfmpegError::ArgumentError)?;
+    }
+    if let Some(n) = matches.value_of("memory-cache") {
+        config.memory_cache = n
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or(FfmpegError::ArgumentError)?;
+    }
+    if let Some(size) = matches.value_of("cache-max") {
+        config.cache_max = Some(server::cache::parse_size(size).ok_or(FfmpegError::ArgumentError)?);
+    }
+    if let Some(format) = matches.value_of("cache-format") {
+        config.cache_format = config::parse_format(format).ok_or(FfmpegError::ArgumentError)?;
+    }
+    if let Some(level) = matches.value_of("log-level") {
+        config.log_level = level.parse().map_err(|_| FfmpegError::ArgumentError)?;
     }
+    if let Some(file) = matches.value_of("log-file") {
+        config.log_file = Some(PathBuf::from(file));
+    }
+    if let Some(format) = matches.value_of("log-format") {
+        config.log_format = config::parse_log_format(format).ok_or(FfmpegError::ArgumentError)?;
+    }
+    Ok(config)
 }
 
-#[tokio::main]
-async fn main() -> CommandResult {
-    use clap::{App, Arg, SubCommand};
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_writer(write_to_log)
+/// Logs to stderr, or appends to the configured file, creating it and its
+/// directory if needed.
+fn init_logging(config: &Config) -> CommandResult {
+    let (writer, ansi) = match &config.log_file {
+        Some(path) => {
+            let open = || {
+                if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::File::options().create(true).append(true).open(path)
+            };
+            let file = open().map_err(|e| {
+                FfmpegError::Unhandled(format!("log file {}: {e}", path.to_string_lossy()))
+            })?;
+            (BoxMakeWriter::new(Arc::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(io::stderr), io::stderr().is_terminal()),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(ansi)
         .with_span_events(FmtSpan::CLOSE)
         .with_target(false)
-        .with_max_level(Level::TRACE)
-        .with_level(false)
-        .with_line_number(true)
-        .finish();
+        .with_max_level(config.log_level)
+        .with_line_number(true);
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    let result = match config.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+    };
+    result.map_err(|e| FfmpegError::Unhandled(e.to_string()))
+}
+
+async fn handle_serve(matches: &clap::ArgMatches<'_>, config: Config) -> CommandResult {
+    let server = match (matches.value_of("INPUT"), matches.value_of("library")) {
+        (Some(file_path), None) => server::FrameServer::new(file_path.to_string())?,
+        (None, Some(root)) => server::FrameServer::library(Path::new(root))?,
+        _ => return Err(FfmpegError::ArgumentError),
+    };
+    server.serve(config).await?;
+    Ok(())
+}
+
+async fn handle_play(matches: &clap::ArgMatches<'_>) -> CommandResult {
+    let file_path = matches
+        .value_of("INPUT")
+        .ok_or(FfmpegError::ArgumentError)?
+        .to_string();
+    let ms = |name| -> CommandResult<usize> {
+        matches
+            .value_of(name)
+            .and_then(|v| v.parse().ok())
+            .ok_or(FfmpegError::ArgumentError)
+    };
+    let (from, to) = (ms("from")?, ms("to")?);
+    if to <= from {
+        return Err(FfmpegError::ArgumentError);
+    }
+
+    tokio::task::spawn_blocking(move || play::play(&file_path, from, to))
+        .await
+        .map_err(|e| FfmpegError::Unhandled(e.to_string()))?
+}
+
+fn handle_cache(matches: &clap::ArgMatches<'_>, config: Config) -> CommandResult {
+    let root = config.cache_dir;
+    let (name, sub_m) = matches.subcommand();
+    let sub_m = sub_m.ok_or(FfmpegError::ArgumentError)?;
+    let file = sub_m.value_of("file");
+    match name {
+        "ls" => cache::ls(&root, file),
+        "stats" => cache::stats(&root, file),
+        "gc" => cache::gc(&root, file, sub_m.is_present("force")),
+        "purge" => {
+            let older_than = match sub_m.value_of("older-than") {
+                Some(age) => Some(cache::parse_age(age).ok_or(FfmpegError::ArgumentError)?),
+                None => None,
+            };
+            cache::purge(&root, file, older_than, sub_m.is_present("force"))
+        }
+        _ => Err(FfmpegError::ArgumentError),
+    }
+}
+
+#[tokio::main]
+async fn main() -> CommandResult {
+    use clap::{App, AppSettings, Arg, SubCommand};
 
     let input_arg = &Arg::with_name("INPUT")
         .required(true)
         .help("Sets the input file to use");
 
+    let file_arg = &Arg::with_name("file")
+        .long("file")
+        .takes_value(true)
+        .value_name("INPUT")
+        .help("Only the cache for INPUT");
+
+    let force_arg = &Arg::with_name("force")
+        .long("force")
+        .help("Also removes directories and files under the cache root that don't record a source");
+
+    let ms_arg = |name| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .required(true)
+            .value_name("MS")
+    };
+
     let app_m = App::new("frm")
-        .subcommand(SubCommand::with_name("serve").arg(input_arg))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Settings file [default: $XDG_CONFIG_HOME/frm/config.toml]"),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .global(true)
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Where frames are cached [default: $CACHE_DIR or $XDG_CACHE_HOME/frm]"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("Most verbose level logged [default: info]"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .global(true)
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Appends logs to FILE instead of stderr"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("How log lines are written [default: text]"),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .required_unless("library")
+                        .conflicts_with("library")
+                        .help("Sets the input file to use"),
+                )
+                .arg(
+                    Arg::with_name("library")
+                        .long("library")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Serves every video under DIR instead of one file"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .help("Address to listen on [default: 127.0.0.1]"),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .takes_value(true)
+                        .help("Port to listen on [default: 3030]"),
+                )
+                .arg(
+                    Arg::with_name("memory-cache")
+                        .long("memory-cache")
+                        .takes_value(true)
+                        .value_name("FRAMES")
+                        .help(
+                            "Frames kept in memory [default: 1200]; in library mode, \
+                             split across the 8 most recently opened videos",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("cache-max")
+                        .long("cache-max")
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("Evicts the least recently served frames past SIZE, e.g. 5GB"),
+                )
+                .arg(
+                    Arg::with_name("cache-format")
+                        .long("cache-format")
+                        .takes_value(true)
+                        .possible_values(&["png", "jpeg", "webp"])
+                        .help("Format frames are served in unless the client asks for another [default: png]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("Plays a range of the input's audio")
+                .arg(input_arg)
+                .arg(ms_arg("from").help("Start of the range in milliseconds"))
+                .arg(ms_arg("to").help("End of the range in milliseconds")),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Inspects and cleans the frame cache")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("Lists frames and bytes per source")
+                        .arg(file_arg),
+                )
+                .subcommand(
+                    SubCommand::with_name("stats")
+                        .about("Summarizes the whole cache")
+                        .arg(file_arg),
+                )
+                .subcommand(
+                    SubCommand::with_name("gc")
+                        .about("Removes frames of sources that were deleted or changed")
+                        .arg(file_arg)
+                        .arg(force_arg),
+                )
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("Removes cached frames by source or age")
+                        .arg(file_arg)
+                        .arg(force_arg)
+                        .arg(
+                            Arg::with_name("older-than")
+                                .long("older-than")
+                                .takes_value(true)
+                                .value_name("AGE")
+                                .help("Only frames written more than AGE ago, e.g. 30d"),
+                        ),
+                ),
+        )
         .get_matches();
 
-    match app_m.subcommand() {
-        ("serve", Some(sub_m)) => handle_serve(sub_m).await,
+    let (name, sub_m) = app_m.subcommand();
+    let sub_m = sub_m.ok_or(FfmpegError::ArgumentError)?;
+    // only the commands that use the config file read it, so a broken one
+    // doesn't stop playback
+    let config = match name {
+        "serve" | "cache" => load_config(sub_m)?,
+        _ => apply_flags(Config::default(), sub_m)?,
+    };
+    init_logging(&config)?;
+
+    match name {
+        "serve" => handle_serve(sub_m, config).await,
+        "play" => handle_play(sub_m).await,
+        "cache" => handle_cache(sub_m, config),
         _ => Err(FfmpegError::ArgumentError),
     }
 }
-
-const LOG_PATH: &str = "development.log";
-
-fn write_to_log() -> impl io::Write {
-    let pwd = current_dir().expect("couldn't get current dir");
-    fs::File::options()
-        .append(true)
-        .open(pwd.join(LOG_PATH))
-        .expect("failed to create file")
-}
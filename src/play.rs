@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    terminal::{self, Clear, ClearType},
+};
+use rodio::{buffer::SamplesBuffer, OutputStream, Sink};
+
+use crate::ffmpeg::{
+    cmd::FormatKind,
+    sequence::{Sequence, PCM_CHANNELS, PCM_SAMPLE_RATE},
+    ErrorKind,
+};
+
+const NUDGE: usize = 1000;
+
+fn decode(file: &str, from: usize, to: usize) -> Result<Vec<i16>, ErrorKind> {
+    let bytes = Sequence::audio(file, from, to)
+        .with_format(FormatKind::Pcm)
+        .read_bytes()?;
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect())
+}
+
+/// Keeps the terminal in raw mode so keys arrive without waiting for Enter,
+/// restoring it when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+enum Key {
+    Replay,
+    Back,
+    Forward,
+    Quit,
+}
+
+fn next_key() -> io::Result<Key> {
+    loop {
+        if let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event::read()?
+        {
+            match code {
+                KeyCode::Enter | KeyCode::Char(' ') => return Ok(Key::Replay),
+                KeyCode::Char('[') => return Ok(Key::Back),
+                KeyCode::Char(']') => return Ok(Key::Forward),
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(Key::Quit),
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(Key::Quit)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Plays `from..to` (ms) until told to quit, reacting to single keypresses.
+/// The range is only decoded again once it has been moved.
+pub fn play(file: &str, mut from: usize, mut to: usize) -> Result<(), ErrorKind> {
+    let (_stream, handle) =
+        OutputStream::try_default().map_err(|e| ErrorKind::Unhandled(e.to_string()))?;
+
+    let mut samples = decode(file, from, to)?;
+    let _raw = RawMode::enable()?;
+    loop {
+        // a fresh sink per take; dropping the last one stops it
+        let sink = Sink::try_new(&handle).map_err(|e| ErrorKind::Unhandled(e.to_string()))?;
+        sink.append(SamplesBuffer::new(
+            PCM_CHANNELS,
+            PCM_SAMPLE_RATE,
+            samples.clone(),
+        ));
+
+        // raw mode doesn't translate newlines, so redraw the prompt in place
+        let mut stdout = io::stdout();
+        queue!(
+            stdout,
+            cursor::MoveToColumn(0),
+            Clear(ClearType::CurrentLine)
+        )?;
+        print!("{from}..{to} ms  [enter] replay  [ -1s  ] +1s  q quit ");
+        stdout.flush()?;
+
+        match next_key()? {
+            Key::Replay => {}
+            Key::Back => {
+                let by = NUDGE.min(from);
+                if by > 0 {
+                    from -= by;
+                    to -= by;
+                    samples = decode(file, from, to)?;
+                }
+            }
+            Key::Forward => {
+                from += NUDGE;
+                to += NUDGE;
+                samples = decode(file, from, to)?;
+            }
+            Key::Quit => {
+                print!("\r\n");
+                return Ok(());
+            }
+        }
+    }
+}
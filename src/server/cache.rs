@@ -0,0 +1,578 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::ffmpeg::{cmd::FormatKind, frame::Render};
+
+/// Records which source a cache directory belongs to, since its name is
+/// only a hash
+pub const SOURCE_FILE: &str = "source.json";
+
+/// Holds a source's encoded audio snippets, alongside its render directories
+const AUDIO_DIR: &str = "audio";
+
+/// 64-bit FNV-1a, which unlike std's hashers is fixed across releases.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// One version of a source file. Rewriting the file changes its size or
+/// modification time, and so its fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+
+    /// Nanoseconds since the Unix epoch
+    pub modified: u128,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let path = path.canonicalize()?;
+        let meta = fs::metadata(&path)?;
+        let modified = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        Ok(Fingerprint {
+            path,
+            size: meta.len(),
+            modified,
+        })
+    }
+
+    /// The source's file stem, for people browsing the cache, followed by a
+    /// hash of the whole fingerprint.
+    pub fn key(&self) -> String {
+        let stem: String = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .take(40)
+            .collect();
+        let hash = stable_hash(
+            format!(
+                "{}\0{}\0{}",
+                self.path.to_string_lossy(),
+                self.size,
+                self.modified
+            )
+            .as_bytes(),
+        );
+        format!("{stem}-{hash:016x}")
+    }
+}
+
+/// Where everything derived from one version of a source is kept on disk:
+///
+/// ```text
+/// {root}/{fingerprint}/source.json
+/// {root}/{fingerprint}/{kind}.json
+/// {root}/{fingerprint}/{render}/{timecode}.{ext}
+/// {root}/{fingerprint}/audio/{stream}-{from}-{to}.{ext}
+/// ```
+#[derive(Debug, Clone)]
+pub struct SourceCache {
+    dir: PathBuf,
+}
+
+impl SourceCache {
+    /// Creates the directory for `fingerprint` under `root` if needed.
+    pub fn open(root: &Path, fingerprint: &Fingerprint) -> io::Result<Self> {
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Path to cache root \"{}\" either does not exist or is not a directory",
+                    root.to_string_lossy()
+                ),
+            ));
+        }
+
+        let dir = root.join(fingerprint.key());
+        let source = dir.join(SOURCE_FILE);
+        if !source.exists() {
+            fs::create_dir_all(&dir)?;
+            let bs = serde_json::to_vec_pretty(fingerprint).map_err(io::Error::other)?;
+            fs::write(source, bs)?;
+        }
+        Ok(SourceCache { dir })
+    }
+
+    /// Where a whole-file analysis named `kind` is persisted.
+    pub fn analysis_path(&self, kind: &str) -> PathBuf {
+        self.dir.join(format!("{kind}.json"))
+    }
+
+    /// The directory holding frames rendered with `render`, created if needed.
+    pub fn frames_dir(&self, render: &Render) -> io::Result<PathBuf> {
+        let dir = self.dir.join(render.key());
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    pub fn frame_path(&self, render: &Render, timecode: usize) -> PathBuf {
+        self.dir
+            .join(render.key())
+            .join(format!("{timecode}.{}", render.format.extension()))
+    }
+
+    /// Where an audio snippet of the `stream`th audio stream is stored.
+    pub fn audio_path(
+        &self,
+        stream: usize,
+        from: usize,
+        to: usize,
+        format: &FormatKind,
+    ) -> PathBuf {
+        self.dir
+            .join(AUDIO_DIR)
+            .join(format!("{stream}-{from}-{to}.{}", format.extension()))
+    }
+
+    /// A strong validator for a frame. It hashes the same fingerprint, render
+    /// and timecode that place the frame on disk, so it changes with any of
+    /// them.
+    pub fn frame_etag(&self, render: &Render, timecode: usize) -> String {
+        let fingerprint = self.dir.file_name().unwrap_or_default().to_string_lossy();
+        let hash = stable_hash(format!("{fingerprint}\0{}\0{timecode}", render.key()).as_bytes());
+        format!("\"{hash:016x}\"")
+    }
+}
+
+/// The per-source directories under `root`.
+pub fn source_dirs(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if root.is_dir() {
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The source `dir` was made from, if it records one. Directories without
+/// it may not be ours, since the root can be shared.
+pub fn recorded_source(dir: &Path) -> Option<Fingerprint> {
+    fs::read(dir.join(SOURCE_FILE))
+        .ok()
+        .and_then(|bs| serde_json::from_slice(&bs).ok())
+}
+
+/// Every stored frame of one source, across render settings, along with its
+/// audio snippets, which share the frames' budget.
+pub fn frame_files(source_dir: &Path) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+    let mut frames = Vec::new();
+    for render in fs::read_dir(source_dir)? {
+        let render = render?;
+        if !render.file_type()?.is_dir() {
+            continue;
+        }
+        for frame in fs::read_dir(render.path())? {
+            let frame = frame?;
+            let meta = frame.metadata()?;
+            if meta.is_file() {
+                frames.push((frame.path(), meta));
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// How a cache directory relates to the file it was made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceStatus {
+    Current,
+
+    /// The file has been rewritten since, so nothing here will be served
+    Changed,
+
+    Missing,
+
+    /// No readable `source.json`
+    Unknown,
+}
+
+impl fmt::Display for SourceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceStatus::Current => write!(f, "current"),
+            SourceStatus::Changed => write!(f, "changed"),
+            SourceStatus::Missing => write!(f, "missing"),
+            SourceStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// What one cache directory holds.
+#[derive(Debug, Clone)]
+pub struct SourceUsage {
+    pub dir: PathBuf,
+    pub fingerprint: Option<Fingerprint>,
+    pub status: SourceStatus,
+    pub frames: usize,
+
+    /// Frames plus audio snippets and analyses
+    pub bytes: u64,
+}
+
+impl SourceUsage {
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        let fingerprint = recorded_source(dir);
+        let status = match &fingerprint {
+            None => SourceStatus::Unknown,
+            Some(recorded) => match Fingerprint::of(&recorded.path) {
+                Ok(now) if now == *recorded => SourceStatus::Current,
+                Ok(_) => SourceStatus::Changed,
+                Err(_) => SourceStatus::Missing,
+            },
+        };
+
+        let files = frame_files(dir)?;
+        let mut bytes: u64 = files.iter().map(|(_, meta)| meta.len()).sum();
+        let audio = dir.join(AUDIO_DIR);
+        let frames = files.iter().filter(|(p, _)| !p.starts_with(&audio)).count();
+        for entry in fs::read_dir(dir)? {
+            let meta = entry?.metadata()?;
+            if meta.is_file() {
+                bytes += meta.len();
+            }
+        }
+
+        Ok(SourceUsage {
+            dir: dir.to_path_buf(),
+            fingerprint,
+            status,
+            frames,
+            bytes,
+        })
+    }
+
+    /// Whether this directory was made from `path`, in any version.
+    pub fn is_for(&self, path: &Path) -> bool {
+        self.fingerprint.as_ref().is_some_and(|f| f.path == path)
+    }
+}
+
+/// Formats a byte count the way `parse_size` reads it.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{size:.1}{}", UNITS[unit])
+    }
+}
+
+/// Parses sizes like `5GB`, `512MiB` or `1048576`. Decimal suffixes are
+/// powers of 1000, binary ones (`KiB`, `MiB`, ...) powers of 1024.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1_000,
+        "M" | "MB" => 1_000_000,
+        "G" | "GB" => 1_000_000_000,
+        "T" | "TB" => 1_000_000_000_000,
+        "KIB" => 1 << 10,
+        "MIB" => 1 << 20,
+        "GIB" => 1 << 30,
+        "TIB" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    size: u64,
+
+    /// When the frame was last written or served, as a tick of `Usage`
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    total: u64,
+    tick: u64,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+/// Once over budget, frames are evicted until usage falls to this fraction
+/// of it, so eviction doesn't run again on the very next write
+const EVICT_TO: f64 = 0.9;
+
+/// Tracks the frames and audio snippets stored under the cache root and keeps
+/// their total size within a budget by deleting the least recently served.
+/// Analyses and other small files aren't counted.
+#[derive(Debug)]
+pub struct DiskCache {
+    root: PathBuf,
+    max_bytes: Option<u64>,
+    format: FormatKind,
+    usage: Mutex<Usage>,
+}
+
+impl DiskCache {
+    /// Indexes the frames already on disk, taking their modification times
+    /// as when they were last served. Only directories that record their
+    /// source are indexed, so nothing else under the root is ever evicted.
+    pub fn scan(root: &Path, max_bytes: Option<u64>, format: FormatKind) -> io::Result<Self> {
+        let mut found = Vec::new();
+        let sources = source_dirs(root)?
+            .into_iter()
+            .filter(|dir| recorded_source(dir).is_some());
+        for source in sources {
+            for (path, meta) in frame_files(&source)? {
+                found.push((meta.modified()?, path, meta.len()));
+            }
+        }
+        found.sort();
+
+        let mut usage = Usage::default();
+        for (_, path, size) in found {
+            usage.tick += 1;
+            usage.total += size;
+            usage.entries.insert(
+                path,
+                Entry {
+                    size,
+                    used: usage.tick,
+                },
+            );
+        }
+        info!(
+            "disk cache holds {} frames, {} bytes",
+            usage.entries.len(),
+            usage.total
+        );
+
+        let cache = DiskCache {
+            root: root.to_path_buf(),
+            max_bytes,
+            format,
+            usage: Mutex::new(usage),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// The directory holding every source's cache.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The format frames are stored in.
+    pub fn format(&self) -> FormatKind {
+        self.format.clone()
+    }
+
+    /// Marks a stored frame as just served.
+    pub fn touch(&self, path: &Path) {
+        let mut usage = self.usage.lock().expect("disk cache lock poisoned");
+        usage.tick += 1;
+        let tick = usage.tick;
+        if let Some(entry) = usage.entries.get_mut(path) {
+            entry.used = tick;
+        }
+    }
+
+    /// Counts newly written frames, evicting older ones if that goes over
+    /// budget.
+    pub fn record<I: IntoIterator<Item = PathBuf>>(&self, paths: I) {
+        {
+            let mut usage = self.usage.lock().expect("disk cache lock poisoned");
+            for path in paths {
+                let size = match fs::metadata(&path) {
+                    Ok(meta) => meta.len(),
+                    Err(e) => {
+                        info!("failed to stat {}: {e}", path.to_string_lossy());
+                        continue;
+                    }
+                };
+                usage.tick += 1;
+                let entry = Entry {
+                    size,
+                    used: usage.tick,
+                };
+                if let Some(previous) = usage.entries.insert(path, entry) {
+                    usage.total -= previous.size;
+                }
+                usage.total += size;
+            }
+        }
+        self.evict();
+    }
+
+    /// Forgets frames under `dir`, which has been removed.
+    pub fn forget_dir(&self, dir: &Path) {
+        let mut usage = self.usage.lock().expect("disk cache lock poisoned");
+        let gone: Vec<_> = usage
+            .entries
+            .keys()
+            .filter(|p| p.starts_with(dir))
+            .cloned()
+            .collect();
+        for path in gone {
+            if let Some(entry) = usage.entries.remove(&path) {
+                usage.total -= entry.size;
+            }
+        }
+    }
+
+    fn evict(&self) {
+        let max = match self.max_bytes {
+            Some(max) => max,
+            None => return,
+        };
+
+        let victims = {
+            let mut usage = self.usage.lock().expect("disk cache lock poisoned");
+            if usage.total <= max {
+                return;
+            }
+            let target = (max as f64 * EVICT_TO) as u64;
+
+            let mut by_age: Vec<_> = usage
+                .entries
+                .iter()
+                .map(|(path, entry)| (entry.used, path.clone()))
+                .collect();
+            by_age.sort();
+
+            let mut victims = Vec::new();
+            for (_, path) in by_age {
+                if usage.total <= target {
+                    break;
+                }
+                if let Some(entry) = usage.entries.remove(&path) {
+                    usage.total -= entry.size;
+                    victims.push(path);
+                }
+            }
+            victims
+        };
+
+        info!("evicting {} frames from the disk cache", victims.len());
+        for path in victims {
+            if let Err(e) = fs::remove_file(&path) {
+                info!("failed to remove {}: {e}", path.to_string_lossy());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(path: &str, size: u64, modified: u128) -> Fingerprint {
+        Fingerprint {
+            path: PathBuf::from(path),
+            size,
+            modified,
+        }
+    }
+
+    #[test]
+    fn keys_are_stable_and_readable() {
+        let key = fingerprint(
+            "/videos/My Film (2019).mkv",
+            1024,
+            1_700_000_000_000_000_000,
+        )
+        .key();
+        // persisted on disk, so it must not change between builds
+        assert_eq!(key, "My_Film__2019_-d6ebda35a7046e5b");
+
+        let long = fingerprint(&format!("/videos/{}.mp4", "a".repeat(60)), 1, 1).key();
+        assert_eq!(long.split('-').next().unwrap(), "a".repeat(40));
+    }
+
+    #[test]
+    fn keys_change_with_the_file() {
+        let key = fingerprint("/videos/a.mkv", 1024, 1).key();
+        assert_ne!(key, fingerprint("/videos/a.mkv", 1025, 1).key());
+        assert_ne!(key, fingerprint("/videos/a.mkv", 1024, 2).key());
+        assert_ne!(key, fingerprint("/other/a.mkv", 1024, 1).key());
+        assert_eq!(key, fingerprint("/videos/a.mkv", 1024, 1).key());
+    }
+
+    #[test]
+    fn budget_ignores_foreign_directories() {
+        let root = std::env::temp_dir().join(format!("frm-cache-test-{}", std::process::id()));
+        let write = |path: &Path, bytes: &[u8]| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, bytes).unwrap();
+        };
+        let ours = root.join("a-0000000000000000");
+        write(
+            &ours.join(SOURCE_FILE),
+            &serde_json::to_vec(&fingerprint("/videos/a.mkv", 1, 1)).unwrap(),
+        );
+        write(&ours.join("png-640").join("1000.png"), &[0; 100]);
+        write(&ours.join("png-640").join("2000.png"), &[0; 100]);
+        let foreign = root.join("photos").join("2019").join("beach.png");
+        write(&foreign, &[0; 1000]);
+
+        let cache = DiskCache::scan(&root, Some(150), FormatKind::Png).unwrap();
+        assert!(foreign.exists());
+        assert_eq!(frame_files(&ours).unwrap().len(), 1);
+        assert!(cache.usage.lock().unwrap().total <= 150);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parses_decimal_and_binary_sizes() {
+        assert_eq!(parse_size("1048576"), Some(1_048_576));
+        assert_eq!(parse_size("5GB"), Some(5_000_000_000));
+        assert_eq!(parse_size(" 1.5 gb "), Some(1_500_000_000));
+        assert_eq!(parse_size("512MiB"), Some(512 << 20));
+        assert_eq!(parse_size("2k"), Some(2_000));
+        assert_eq!(parse_size("0.5KiB"), Some(512));
+    }
+
+    #[test]
+    fn rejects_malformed_sizes() {
+        for s in ["", "GB", "5XB", "1.2.3MB", "-5GB", "five"] {
+            assert_eq!(parse_size(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn formatted_sizes_parse_back() {
+        for bytes in [0, 999, 1_500_000, 42_000_000_000] {
+            assert_eq!(parse_size(&format_size(bytes)), Some(bytes));
+        }
+    }
+}
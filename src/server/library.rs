@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::Router;
+use lru::LruCache;
+use serde::Serialize;
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::info;
+
+use crate::ffmpeg::{probe, ErrorKind};
+
+use super::{
+    cache::{stable_hash, DiskCache},
+    routes,
+    state::AppState,
+};
+
+/// Extensions treated as video when scanning, compared case-insensitively
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "avi", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "ts", "webm", "wmv",
+];
+
+/// Videos kept open at once. Each holds its own frame, audio and search
+/// caches, so the least recently used is closed to bound memory; anything
+/// analysed is on disk and reopening it only costs a probe.
+const OPEN_VIDEOS: usize = 8;
+
+/// A video found under the library root.
+#[derive(Debug, Clone, Serialize)]
+pub struct Video {
+    /// Derived from `path`, so it survives rescans and restarts
+    pub id: String,
+
+    /// Relative to the library root, with `/` separators
+    pub path: String,
+
+    pub size: u64,
+}
+
+/// The video files under a root directory.
+#[derive(Debug, Clone)]
+pub struct Library {
+    root: PathBuf,
+    videos: Arc<Vec<Video>>,
+    by_id: Arc<HashMap<String, usize>>,
+}
+
+fn stable_id(relative: &str) -> String {
+    format!("{:016x}", stable_hash(relative.as_bytes()))
+}
+
+fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| VIDEO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+impl Library {
+    /// Walks `root` for video files. Hidden entries are skipped, and so is
+    /// anything that resolves outside the root through a symlink.
+    pub fn scan(root: &Path) -> io::Result<Self> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::other(format!(
+                "{} is not a directory",
+                root.to_string_lossy()
+            )));
+        }
+
+        let mut videos = Vec::new();
+        let mut pending = vec![root.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                // symlinked directories aren't followed, which also rules
+                // out cycles
+                if entry.file_type()?.is_dir() {
+                    pending.push(entry.path());
+                    continue;
+                }
+
+                let path = entry.path();
+                if !is_video(&path) {
+                    continue;
+                }
+                let Ok(resolved) = path.canonicalize() else {
+                    continue;
+                };
+                if !resolved.starts_with(&root) || !resolved.is_file() {
+                    info!("skipping {} outside library", path.to_string_lossy());
+                    continue;
+                }
+
+                let relative = path
+                    .strip_prefix(&root)
+                    .expect("scanned under root")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                videos.push(Video {
+                    id: stable_id(&relative),
+                    path: relative,
+                    size: resolved.metadata()?.len(),
+                });
+            }
+        }
+        videos.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let by_id = videos
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v.id.clone(), i))
+            .collect();
+        Ok(Library {
+            root,
+            videos: Arc::new(videos),
+            by_id: Arc::new(by_id),
+        })
+    }
+
+    pub fn videos(&self) -> &[Video] {
+        &self.videos
+    }
+
+    /// The file behind `id`, provided it still resolves inside the root.
+    pub fn resolve(&self, id: &str) -> Option<PathBuf> {
+        let video = &self.videos[*self.by_id.get(id)?];
+        let resolved = self.root.join(&video.path).canonicalize().ok()?;
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+}
+
+/// Per-video routers, created on first request so that only videos someone
+/// opens are probed, and closed again once `OPEN_VIDEOS` others are newer.
+#[derive(Debug, Clone)]
+pub struct LibraryState {
+    pub library: Library,
+    routers: Arc<Mutex<LruCache<String, Router>>>,
+    /// Frames each open video may keep in memory
+    capacity: usize,
+    disk: Arc<DiskCache>,
+}
+
+impl LibraryState {
+    /// `capacity` is the frame budget for the whole library.
+    pub fn new(library: Library, capacity: usize, disk: Arc<DiskCache>) -> Self {
+        LibraryState {
+            library,
+            routers: Arc::new(Mutex::new(LruCache::new(OPEN_VIDEOS.try_into().unwrap()))),
+            capacity: (capacity / OPEN_VIDEOS).max(1),
+            disk,
+        }
+    }
+
+    /// The single-file routes for `id`, or `None` if it isn't in the library.
+    #[tracing::instrument(skip(self))]
+    pub async fn router(&self, id: &str) -> Result<Option<Router>, ErrorKind> {
+        if let Some(router) = self.routers.lock().await.get(id) {
+            return Ok(Some(router.clone()));
+        }
+        let path = match self.library.resolve(id) {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => return Ok(None),
+        };
+
+        let probe = {
+            let path = path.clone();
+            spawn_blocking(move || probe(&path))
+                .await
+                .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??
+        };
+
+        // probed without holding the lock, so other videos aren't held up; a
+        // concurrent request for the same video may probe it too, and
+        // whichever finishes first is kept
+        let mut routers = self.routers.lock().await;
+        if let Some(router) = routers.get(id) {
+            return Ok(Some(router.clone()));
+        }
+        let router =
+            routes().with_state(AppState::new(path, probe, self.capacity, self.disk.clone()));
+        routers.push(id.to_string(), router.clone());
+        Ok(Some(router))
+    }
+}
@@ -1,20 +1,44 @@
+pub mod cache;
+mod library;
+mod search;
 mod state;
 
+use std::{collections::HashMap, net::ToSocketAddrs, path::Path as FsPath, sync::Arc};
+
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::{Body, StreamBody},
+    extract::{Extension, Path, Query, State},
+    http::{HeaderMap, Request, StatusCode},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{any, get},
     Router,
 };
 
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{
+    HeaderName, ACCEPT, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+};
+use serde::Deserialize;
 use serde_json::json;
 use thiserror::Error;
 
-use crate::ffmpeg::{cmd::Command, duration, sequence::Sequence};
+use crate::config::Config;
+use crate::ffmpeg::{
+    cmd::FormatKind,
+    frame::{Crop, Fit, Render},
+    keyframe::Snap,
+    probe,
+    probe::Probe,
+    sequence::Quality,
+    subtitle::{self, Align, CueSettings},
+    ErrorKind,
+};
 
-use self::state::AppState;
+use self::cache::DiskCache;
+use self::library::{Library, LibraryState};
+use self::state::{AppState, AUDIO_SNIPPET_MAX};
+use tokio::task::spawn_blocking;
+use tower::ServiceExt;
+use tracing::info;
 
 #[allow(dead_code)]
 pub struct SampleWindow {
@@ -23,93 +47,989 @@ pub struct SampleWindow {
     pub end: Option<u32>,
 }
 
-fn get_subs(file_str: String, s: usize, e: usize) -> String {
-    match Sequence::subtitles(file_str, s, e).execute() {
-        Ok(bs) => match std::str::from_utf8(&bs.stdout) {
-            Ok(s) => s.to_string(),
-            Err(e) => json!({ "error": e.to_string() }).to_string(),
-        },
-        Err(e) => json!({ "error": e.to_string() }).to_string(),
-    }
+#[derive(Debug)]
+pub struct FrameServer {
+    source: Source,
 }
 
 #[derive(Debug)]
-pub struct FrameServer {
-    file: String,
+enum Source {
+    File { file: String, probe: Probe },
+    Library(Library),
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[allow(dead_code)]
     #[error("unhandled: {0}")]
     Unhandled(String),
 }
 
 impl Error {
-    #[allow(dead_code)]
     pub fn unhandled(s: impl Into<String>) -> Self {
         Self::Unhandled(s.into())
     }
 }
 
+/// Largest width or height a frame can be rendered at
+const MAX_FRAME_SIZE: u32 = 7680;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl ImageFormat {
+    /// In order of preference among formats a client accepts equally. WebP
+    /// comes first as it encodes far faster than AVIF for a similar size.
+    const PREFERENCE: [ImageFormat; 4] = [
+        ImageFormat::WebP,
+        ImageFormat::Avif,
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+    ];
+
+    fn kind(self) -> FormatKind {
+        match self {
+            ImageFormat::Png => FormatKind::Png,
+            ImageFormat::Jpeg => FormatKind::Jpeg,
+            ImageFormat::WebP => FormatKind::WebP,
+            ImageFormat::Avif => FormatKind::Avif,
+        }
+    }
+}
+
+/// Picks a frame format from an `Accept` header. The highest q wins, and
+/// among those tied for it the default, since that's what range ingestion
+/// renders and the disk cache holds. Otherwise formats named outright beat
+/// those matched by a wildcard, then `ImageFormat::PREFERENCE` decides.
+/// `None` if none are acceptable.
+fn negotiate_format(accept: &str, default: FormatKind) -> Option<FormatKind> {
+    let mut named = HashMap::new();
+    let mut wildcard: Option<f32> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let mime = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match mime.as_str() {
+            "*/*" | "image/*" => wildcard = Some(wildcard.map_or(q, |w| w.max(q))),
+            mime => {
+                named.insert(mime.to_string(), q);
+            }
+        }
+    }
+
+    // q, and whether the format was named rather than matched by a wildcard
+    let acceptance = |kind: &FormatKind| {
+        let (q, is_named) = match named.get(kind.mime()) {
+            Some(q) => (*q, true),
+            None => (wildcard?, false),
+        };
+        (q > 0.0).then_some((q, is_named))
+    };
+
+    let (q, _, _, best) = ImageFormat::PREFERENCE
+        .iter()
+        .rev()
+        .enumerate()
+        .filter_map(|(rank, format)| {
+            let kind = format.kind();
+            let (q, is_named) = acceptance(&kind)?;
+            Some((q, is_named, rank, kind))
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)))?;
+    match acceptance(&default) {
+        Some((default_q, _)) if default_q >= q => Some(default),
+        _ => Some(best),
+    }
+}
+
+/// Without `width` or `height`, frames are 640 wide, or the crop's own size
+/// when `crop` is given.
+#[derive(Debug, Deserialize)]
+struct FrameQuery {
+    #[serde(default)]
+    snap: Snap,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    fit: Fit,
+
+    /// `W:H:X:Y` in source pixels
+    crop: Option<String>,
+
+    /// Overrides the `Accept` header
+    format: Option<ImageFormat>,
+
+    /// From 1 to 100, for JPEG, WebP and AVIF
+    quality: Option<u8>,
+}
+
+impl FrameQuery {
+    fn render(
+        &self,
+        state: &AppState,
+        accept: Option<&str>,
+    ) -> Result<Render, (StatusCode, &'static str)> {
+        let bad_request = |msg| (StatusCode::BAD_REQUEST, msg);
+        let valid = |n: Option<u32>| n.is_none_or(|n| (1..=MAX_FRAME_SIZE).contains(&n));
+        if !valid(self.width) || !valid(self.height) {
+            return Err(bad_request("width and height must be between 1 and 7680"));
+        }
+        if !self.quality.is_none_or(|q| (1..=100).contains(&q)) {
+            return Err(bad_request("quality must be between 1 and 100"));
+        }
+
+        let crop = match self.crop.as_deref() {
+            Some(crop) => Some(Crop::parse(crop).ok_or(bad_request("crop must be W:H:X:Y"))?),
+            None => None,
+        };
+        let size = state
+            .probe()
+            .video()
+            .and_then(|v| Some((v.width?, v.height?)));
+        if let (Some(crop), Some((width, height))) = (crop, size) {
+            if !crop.fits(width, height) {
+                return Err(bad_request("crop extends past the frame"));
+            }
+        }
+
+        let default = state.render();
+        let format = match (self.format, accept) {
+            (Some(format), _) => format.kind(),
+            (None, Some(accept)) => negotiate_format(accept, default.format.clone()).ok_or((
+                StatusCode::NOT_ACCEPTABLE,
+                "frames are available as image/webp, image/avif, image/jpeg or image/png",
+            ))?,
+            (None, None) => default.format.clone(),
+        };
+        let (width, height) = match (self.width, self.height, crop) {
+            (None, None, None) => (default.width, default.height),
+            (width, height, _) => (width, height),
+        };
+        Ok(Render {
+            width,
+            height,
+            fit: self.fit,
+            crop,
+            format,
+            quality: self.quality,
+        })
+    }
+}
+
+/// Frames are reused for a day without asking. A source rewritten in place
+/// keeps its frame URLs, so it isn't `immutable`: once the day is up browsers
+/// revalidate, which is a 304 without rendering while the ETag still matches.
+const FRAME_CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// Whether `If-None-Match` lists `etag`, compared weakly as that header asks.
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
 #[tracing::instrument(skip_all)]
-async fn handle_image(State(state): State<AppState>, Path(timestamp): Path<usize>) -> Response {
-    match state.request_frame(timestamp).await {
-        Ok(bytes) => ([(CONTENT_TYPE, "image/png")], bytes).into_response(),
+async fn handle_image(
+    State(state): State<AppState>,
+    Path(timestamp): Path<usize>,
+    Query(query): Query<FrameQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let accept = headers.get(ACCEPT).and_then(|v| v.to_str().ok());
+    let render = match query.render(&state, accept) {
+        Ok(render) => render,
+        Err(e) => return e.into_response(),
+    };
+    let content_type = render.format.mime();
+    let timestamp = match state.snap(timestamp, query.snap).await {
+        Ok(t) => t,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let etag = match state.frame_etag(timestamp, &render).await {
+        Ok(etag) => etag,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let cache_headers = [
+        (ETAG, etag.clone()),
+        (CACHE_CONTROL, FRAME_CACHE_CONTROL.to_string()),
+        (VARY, ACCEPT.to_string()),
+        (
+            HeaderName::from_static("x-frm-timecode"),
+            timestamp.to_string(),
+        ),
+    ];
+    if none_match(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    match state.request_frame(timestamp, render).await {
+        Ok(bytes) => (cache_headers, [(CONTENT_TYPE, content_type)], bytes).into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SampleMode {
+    #[default]
+    Uniform,
+    Shots,
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeQuery {
+    #[serde(default)]
+    mode: SampleMode,
+    threshold: Option<f32>,
+}
+
 #[tracing::instrument(skip(state))]
 async fn handle_image_range(
     State(state): State<AppState>,
     Path((from, to, n)): Path<(usize, usize, usize)>,
+    Query(query): Query<RangeQuery>,
+) -> Response {
+    sample_range(state, from, to, n, query).await
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_chapter_range(
+    State(state): State<AppState>,
+    Path((id, n)): Path<(usize, usize)>,
+    Query(query): Query<RangeQuery>,
+) -> Response {
+    match state.chapters().into_iter().find(|c| c.id == id) {
+        Some(chapter) => sample_range(state, chapter.start, chapter.end, n, query).await,
+        None => (StatusCode::NOT_FOUND, "no such chapter").into_response(),
+    }
+}
+
+async fn sample_range(
+    state: AppState,
+    from: usize,
+    to: usize,
+    n: usize,
+    query: RangeQuery,
 ) -> Response {
-    let step = (to - from) / n;
-    match state.ingest_frame_range(from, n, step).await {
-        Ok(()) => StatusCode::CREATED.into_response(),
+    if n == 0 || to <= from {
+        return (StatusCode::BAD_REQUEST, "invalid range or frame count").into_response();
+    }
+    // uniform sampling keeps its original bodiless response; shots are
+    // only known once detected, so they're listed
+    let ingested = match query.mode {
+        SampleMode::Uniform => {
+            let step = (to - from) / n;
+            state
+                .ingest_frame_range(from, n, step)
+                .await
+                .map(|_| StatusCode::CREATED.into_response())
+        }
+        SampleMode::Shots => {
+            let threshold = query.threshold.unwrap_or(SCENE_THRESHOLD);
+            state
+                .ingest_shots(from, to, n, threshold)
+                .await
+                .map(|codes| (StatusCode::CREATED, axum::Json(codes)).into_response())
+        }
+    };
+    match ingested {
+        Ok(response) => response,
+        Err(ErrorKind::ArgumentError) => {
+            (StatusCode::BAD_REQUEST, "threshold must be between 0 and 1").into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_chapters(State(state): State<AppState>) -> Response {
+    axum::Json(state.chapters()).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SubtitleFormat {
+    #[default]
+    Json,
+    Srt,
+    Vtt,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubtitleQuery {
+    #[serde(default)]
+    format: SubtitleFormat,
+    line: Option<u8>,
+    position: Option<u8>,
+    size: Option<u8>,
+    align: Option<Align>,
+    stream: Option<usize>,
+    lang: Option<String>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_subtitles(
+    State(state): State<AppState>,
+    Path((from, to)): Path<(usize, usize)>,
+    Query(query): Query<SubtitleQuery>,
+) -> Response {
+    if to <= from {
+        return (StatusCode::BAD_REQUEST, "range end must be after its start").into_response();
+    }
+    let stream = match subtitle::select_stream(
+        state.subtitle_streams(),
+        query.stream,
+        query.lang.as_deref(),
+    ) {
+        Some(stream) => stream,
+        None => return (StatusCode::NOT_FOUND, "no matching subtitle stream").into_response(),
+    };
+
+    let settings = CueSettings {
+        line: query.line,
+        position: query.position,
+        size: query.size,
+        align: query.align,
+    };
+    // without cue settings to add, ffmpeg's own WebVTT will do
+    if matches!(query.format, SubtitleFormat::Vtt) && settings.is_empty() {
+        return match state.subtitle_track(from, to, stream).await {
+            Ok(vtt) => ([(CONTENT_TYPE, "text/vtt; charset=utf-8")], vtt).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+    }
+
+    let subs = match state.subtitles(from, to, stream).await {
+        Ok(subs) => subs,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    match query.format {
+        SubtitleFormat::Json => axum::Json(
+            subs.iter()
+                .map(|s| {
+                    json!({
+                        "index": s.index(),
+                        "start": s.start().as_millis() as u64,
+                        "end": s.end().as_millis() as u64,
+                        "text": s.content(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        SubtitleFormat::Srt => (
+            [(CONTENT_TYPE, "application/x-subrip; charset=utf-8")],
+            subtitle::to_srt(&subs),
+        )
+            .into_response(),
+        SubtitleFormat::Vtt => (
+            [(CONTENT_TYPE, "text/vtt; charset=utf-8")],
+            subtitle::to_vtt(&subs, &settings),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClipMode {
+    #[default]
+    Encode,
+    Copy,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipQuery {
+    #[serde(default)]
+    mode: ClipMode,
+    #[serde(default)]
+    quality: Quality,
+    width: Option<u32>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_clip(
+    State(state): State<AppState>,
+    Path((from, to)): Path<(usize, usize)>,
+    Query(query): Query<ClipQuery>,
+) -> Response {
+    if to <= from {
+        return (StatusCode::BAD_REQUEST, "range end must be after its start").into_response();
+    }
+    if !query
+        .width
+        .is_none_or(|w| (2..=MAX_FRAME_SIZE).contains(&w))
+    {
+        return (StatusCode::BAD_REQUEST, "width must be between 2 and 7680").into_response();
+    }
+    if let ClipMode::Copy = query.mode {
+        return handle_smart_cut(state, from, to).await;
+    }
+    match state.clip(from, to, query.quality, query.width) {
+        Ok(stream) => (
+            [
+                (CONTENT_TYPE, "video/mp4".to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"clip-{from}-{to}.mp4\""),
+                ),
+            ],
+            StreamBody::new(stream),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn handle_smart_cut(state: AppState, from: usize, to: usize) -> Response {
+    let cut = match state.smart_cut(from, to).await {
+        Ok(cut) => cut,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let kind = if cut.lossless() {
+        "lossless"
+    } else {
+        "partial"
+    };
+    match cut.stream() {
+        Ok(stream) => (
+            [
+                (CONTENT_TYPE, "video/x-matroska".to_string()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"clip-{from}-{to}.mkv\""),
+                ),
+                (HeaderName::from_static("x-frm-cut"), kind.to_string()),
+            ],
+            StreamBody::new(stream),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AudioFormat {
+    #[default]
+    Opus,
+    Wav,
+}
+
+#[derive(Debug, Deserialize)]
+struct AudioQuery {
+    #[serde(default)]
+    format: AudioFormat,
+    #[serde(default)]
+    stream: usize,
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_audio(
+    State(state): State<AppState>,
+    Path((from, to)): Path<(usize, usize)>,
+    Query(query): Query<AudioQuery>,
+) -> Response {
+    if to <= from {
+        return (StatusCode::BAD_REQUEST, "range end must be after its start").into_response();
+    }
+    let (format, content_type) = match query.format {
+        AudioFormat::Opus => (FormatKind::Ogg, "audio/ogg"),
+        AudioFormat::Wav => (FormatKind::Wav, "audio/wav"),
+    };
+
+    if to - from <= AUDIO_SNIPPET_MAX {
+        return match state.request_audio(from, to, query.stream, format).await {
+            Ok(bytes) => ([(CONTENT_TYPE, content_type)], bytes).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+    }
+    match state.audio(from, to, query.stream, format) {
+        Ok(stream) => ([(CONTENT_TYPE, content_type)], StreamBody::new(stream)).into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct SheetQuery {
+    width: Option<u32>,
+    #[serde(default)]
+    captions: bool,
+}
+
+const SHEET_TILE_WIDTH: u32 = 320;
+const MAX_SHEET_TILE_WIDTH: u32 = 1920;
+const MAX_SHEET_TILES: usize = 400;
+
+/// The tile width to use, or a 400 if the sheet would be unreasonably large.
+fn sheet_width(n: usize, query: &SheetQuery) -> Result<u32, (StatusCode, &'static str)> {
+    let width = query.width.unwrap_or(SHEET_TILE_WIDTH);
+    if n > MAX_SHEET_TILES {
+        return Err((StatusCode::BAD_REQUEST, "too many tiles"));
+    }
+    if width > MAX_SHEET_TILE_WIDTH {
+        return Err((StatusCode::BAD_REQUEST, "tile width too large"));
+    }
+    Ok(width)
+}
+
+fn sheet_error(e: ErrorKind) -> Response {
+    match e {
+        ErrorKind::ArgumentError => {
+            (StatusCode::BAD_REQUEST, "invalid range or tile count").into_response()
+        }
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_sheet(
+    State(state): State<AppState>,
+    Path((from, to, n)): Path<(usize, usize, usize)>,
+    Query(query): Query<SheetQuery>,
+) -> Response {
+    let width = match sheet_width(n, &query) {
+        Ok(width) => width,
+        Err(e) => return e.into_response(),
+    };
+    match state.sheet(from, to, n, width, query.captions).await {
+        Ok(bytes) => ([(CONTENT_TYPE, "image/png")], bytes).into_response(),
+        Err(e) => sheet_error(e),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_sheet_layout(
+    State(state): State<AppState>,
+    Path((from, to, n)): Path<(usize, usize, usize)>,
+    Query(query): Query<SheetQuery>,
+) -> Response {
+    let width = match sheet_width(n, &query) {
+        Ok(width) => width,
+        Err(e) => return e.into_response(),
+    };
+    match state.sheet_layout(from, to, n, width, query.captions).await {
+        Ok(layout) => axum::Json(layout).into_response(),
+        Err(e) => sheet_error(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ScenesQuery {
+    from: Option<usize>,
+    to: Option<usize>,
+    threshold: Option<f32>,
+}
+
+const SCENE_THRESHOLD: f32 = 0.3;
+
+#[tracing::instrument(skip(state))]
+async fn handle_scenes(
+    State(state): State<AppState>,
+    Query(query): Query<ScenesQuery>,
+) -> Response {
+    let threshold = query.threshold.unwrap_or(SCENE_THRESHOLD);
+    let (from, to) = (query.from.unwrap_or(0), query.to.unwrap_or(usize::MAX));
+    match state.scenes(threshold).await {
+        Ok(changes) => axum::Json(
+            changes
+                .iter()
+                .filter(|c| (from..to).contains(&c.timecode))
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(ErrorKind::ArgumentError) => {
+            (StatusCode::BAD_REQUEST, "threshold must be between 0 and 1").into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BreaksQuery {
+    from: Option<usize>,
+    to: Option<usize>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_breaks(
+    State(state): State<AppState>,
+    Query(query): Query<BreaksQuery>,
+) -> Response {
+    let (from, to) = (query.from.unwrap_or(0), query.to.unwrap_or(usize::MAX));
+    match state.breaks().await {
+        Ok(breaks) => axum::Json(breaks.within(from, to)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+    stream: Option<usize>,
+    lang: Option<String>,
+}
+
+/// Where a source's routes are mounted, when that isn't `/`, so links in
+/// responses can point back at it.
+#[derive(Debug, Clone)]
+struct BasePath(String);
+
+#[tracing::instrument(skip(state, base))]
+async fn handle_search(
+    State(state): State<AppState>,
+    base: Option<Extension<BasePath>>,
+    Query(query): Query<SearchQuery>,
+) -> Response {
+    let base = base
+        .map(|Extension(BasePath(base))| base)
+        .unwrap_or_default();
+    let stream = match subtitle::select_stream(
+        state.subtitle_streams(),
+        query.stream,
+        query.lang.as_deref(),
+    ) {
+        Some(stream) => stream,
+        None => return (StatusCode::NOT_FOUND, "no matching subtitle stream").into_response(),
+    };
+
+    let index = match state.search_index(stream).await {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let hits = index
+        .search(&query.q, query.limit.unwrap_or(50))
+        .into_iter()
+        .map(|hit| {
+            let (start, end) = (hit.cue.start().as_millis(), hit.cue.end().as_millis());
+            json!({
+                "index": hit.cue.index(),
+                "start": start as u64,
+                "end": end as u64,
+                "text": hit.cue.content(),
+                "score": hit.score,
+                "frame": format!("{base}/frame/{}", (start + end) / 2),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    axum::Json(json!({ "indexed": index.len(), "hits": hits })).into_response()
+}
+
+async fn handle_context(State(state): State<AppState>) -> axum::Json<serde_json::Value> {
+    let probe = state.probe();
+    let ms = |d: Option<std::time::Duration>| d.map(|d| d.as_millis() as u64);
+
+    let video = probe.video().map(|v| {
+        json!({
+            "index": v.index,
+            "codec": v.codec_name,
+            "profile": v.profile,
+            "pix_fmt": v.pix_fmt,
+            "width": v.width,
+            "height": v.height,
+            "display_aspect_ratio": v.display_aspect_ratio(),
+            "frame_rate": v.frame_rate(),
+        })
+    });
+    let audio = probe
+        .audio()
+        .into_iter()
+        .enumerate()
+        .map(|(index, a)| {
+            json!({
+                "index": index,
+                "stream_index": a.index,
+                "codec": a.codec_name,
+                "channels": a.channels,
+                "channel_layout": a.channel_layout,
+                "sample_rate": a.sample_rate(),
+                "language": a.language(),
+                "title": a.title(),
+                "default": a.is_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    axum::Json(json!({
+        "duration": ms(probe.duration()),
+        "start_time": ms(probe.start_time()),
+        "format": probe.format.format_name,
+        "title": probe.title(),
+        "bit_rate": probe.bit_rate(),
+        "video": video,
+        "audio": audio,
+        "subtitles": probe.subtitle_streams(),
+        "chapters": probe.chapters(),
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+async fn handle_videos(State(state): State<LibraryState>) -> Response {
+    axum::Json(state.library.videos()).into_response()
+}
+
+/// Hands the rest of the path to the single-file routes for video `id`.
+#[tracing::instrument(skip(state, req))]
+async fn handle_video(
+    State(state): State<LibraryState>,
+    Path((id, _)): Path<(String, String)>,
+    mut req: Request<Body>,
+) -> Response {
+    let router = match state.router(&id).await {
+        Ok(Some(router)) => router,
+        Ok(None) => return (StatusCode::NOT_FOUND, "no such video").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let prefix = format!("/videos/{id}");
+    let rest = req.uri().path().strip_prefix(&prefix).unwrap_or("/");
+    let uri = match req.uri().query() {
+        Some(query) => format!("{rest}?{query}"),
+        None => rest.to_string(),
+    };
+    match uri.parse() {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid path").into_response(),
+    }
+    req.extensions_mut().insert(BasePath(prefix));
+
+    match router.oneshot(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+/// Every route that serves a single source.
+fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/frame/:at", get(handle_image))
+        .route("/frames/:from/:to/:step", get(handle_image_range))
+        .route("/frames/chapter/:id/:step", get(handle_chapter_range))
+        .route("/subtitles/:from/:to", get(handle_subtitles))
+        .route("/clip/:from/:to", get(handle_clip))
+        .route("/audio/:from/:to", get(handle_audio))
+        .route("/sheet/:from/:to/:n", get(handle_sheet))
+        .route("/sheet/:from/:to/:n/layout", get(handle_sheet_layout))
+        .route("/chapters", get(handle_chapters))
+        .route("/scenes", get(handle_scenes))
+        .route("/analysis/breaks", get(handle_breaks))
+        .route("/search", get(handle_search))
+        .route("/context", get(handle_context))
+}
+
 impl FrameServer {
+    /// Probes `file` up front so a missing or unreadable source fails here
+    /// rather than on the first request.
     pub fn new(file: String) -> Result<FrameServer, Error> {
-        Ok(FrameServer { file })
+        let probe = probe(&file).map_err(|e| Error::unhandled(e.to_string()))?;
+        Ok(FrameServer {
+            source: Source::File { file, probe },
+        })
+    }
+
+    /// Serves every video under `root`, each at `/videos/:id/...`.
+    pub fn library(root: &FsPath) -> Result<FrameServer, Error> {
+        let library = Library::scan(root).map_err(|e| {
+            Error::unhandled(format!(
+                "couldn't scan library {}: {e}",
+                root.to_string_lossy()
+            ))
+        })?;
+        Ok(FrameServer {
+            source: Source::Library(library),
+        })
     }
 
     #[tracing::instrument(skip_all)]
-    pub async fn serve(self) {
-        let app: Router<AppState, axum::body::Body> = Router::new()
-            .route("/frame/:at", get(handle_image))
-            .route("/frames/:from/:to/:step", get(handle_image_range))
-            .route(
-                "/context",
-                get(|State(state): State<AppState>| async move {
-                    let d = duration(state.file());
-                    match d {
-                        Ok(d) => axum::Json(json!({ "duration": d.as_millis() as u32 })),
-                        Err(e) => axum::Json(json!({ "error": e.to_string() })),
-                    }
-                }),
-            );
-
-        let builder = axum::Server::bind(&"127.0.0.1:3030".parse().unwrap());
-        let ready_app = app.with_state::<()>(AppState::new(self.file.clone(), 1200));
-        let server = builder.serve(ready_app.into_make_service());
-        server.await.unwrap();
-    }
-
-    // fn srt_route(&self) -> BoxedFilter<(impl Reply,)> {
-    //     let file = self.file.clone();
-    //     let pool = self.pool.clone();
-    //
-    //     let get_subs = move |s, e| pool.install(|| get_subs(file.clone(), s, e));
-    //
-    //     path("subtitles")
-    //         .and(warp::get())
-    //         .and(path::param())
-    //         .and(path::param())
-    //         .map(get_subs)
-    //         .boxed()
-    // }
+    pub async fn serve(self, config: Config) -> Result<(), Error> {
+        let addr = (config.host.as_str(), config.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| {
+                Error::unhandled(format!("can't resolve {}:{}", config.host, config.port))
+            })?;
+
+        let root = config
+            .ensure_cache_dir()
+            .map_err(|e| {
+                Error::unhandled(format!(
+                    "can't create cache dir {}: {e}",
+                    config.cache_dir.to_string_lossy()
+                ))
+            })?
+            .to_path_buf();
+        let (max, format) = (config.cache_max, config.cache_format.clone());
+        let disk = spawn_blocking(move || DiskCache::scan(&root, max, format))
+            .await
+            .map_err(|e| Error::unhandled(e.to_string()))?
+            .map_err(|e| Error::unhandled(format!("couldn't scan frame cache: {e}")))?;
+        let disk = Arc::new(disk);
+        let capacity = config.memory_cache;
+
+        let app: Router = match self.source {
+            Source::File { file, probe } => {
+                routes().with_state(AppState::new(file, probe, capacity, disk))
+            }
+            Source::Library(library) => Router::new()
+                .route("/videos", get(handle_videos))
+                .route("/videos/:id/*rest", any(handle_video))
+                .with_state(LibraryState::new(library, capacity, disk)),
+        };
+
+        let builder = axum::Server::try_bind(&addr)
+            .map_err(|e| Error::unhandled(format!("can't listen on {addr}: {e}")))?;
+        info!("listening on {addr}");
+        builder
+            .serve(app.into_make_service())
+            .await
+            .map_err(|e| Error::unhandled(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept: &str) -> Option<FormatKind> {
+        negotiate_format(accept, FormatKind::Png)
+    }
+
+    #[test]
+    fn prefers_the_default_at_the_highest_q() {
+        assert_eq!(negotiate("image/webp, image/png"), Some(FormatKind::Png));
+        assert_eq!(negotiate("*/*"), Some(FormatKind::Png));
+        // even over formats named outright
+        assert_eq!(
+            negotiate("image/*;q=0.9, image/jpeg;q=0.9"),
+            Some(FormatKind::Png)
+        );
+    }
+
+    #[test]
+    fn default_loses_to_a_higher_q() {
+        assert_eq!(
+            negotiate("image/webp,image/png;q=0.1"),
+            Some(FormatKind::WebP)
+        );
+        // what browsers send for <img>
+        assert_eq!(
+            negotiate("image/avif,image/webp,image/apng,*/*;q=0.8"),
+            Some(FormatKind::WebP)
+        );
+    }
+
+    #[test]
+    fn highest_q_wins() {
+        assert_eq!(
+            negotiate("image/webp;q=0.5, image/avif;q=0.9"),
+            Some(FormatKind::Avif)
+        );
+        assert_eq!(
+            negotiate("image/jpeg;q=0.8, image/webp; q=0.7"),
+            Some(FormatKind::Jpeg)
+        );
+        // equal q falls back to the preference order
+        assert_eq!(negotiate("image/jpeg, image/webp"), Some(FormatKind::WebP));
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        assert_eq!(negotiate("*/*, image/png;q=0"), Some(FormatKind::WebP));
+        assert_eq!(
+            negotiate("image/*, image/png;q=0, image/webp;q=0.0"),
+            Some(FormatKind::Avif)
+        );
+    }
+
+    #[test]
+    fn named_beats_wildcard_at_equal_q() {
+        assert_eq!(
+            negotiate("image/*;q=0.9, image/jpeg;q=0.9, image/png;q=0"),
+            Some(FormatKind::Jpeg)
+        );
+        // but not a wildcard with a higher q
+        assert_eq!(
+            negotiate("image/*, image/jpeg;q=0.9, image/png;q=0"),
+            Some(FormatKind::WebP)
+        );
+    }
+
+    #[test]
+    fn nothing_acceptable_is_none() {
+        assert_eq!(negotiate("text/html"), None);
+        assert_eq!(negotiate("image/*;q=0"), None);
+        assert_eq!(negotiate("image/gif, image/png;q=0"), None);
+    }
+
+    #[test]
+    fn nothing_acceptable_is_406() {
+        let disk = DiskCache::scan(FsPath::new("/nonexistent"), None, FormatKind::Png).unwrap();
+        let state = AppState::new("in.mkv".into(), Probe::default(), 1, Arc::new(disk));
+        let query = FrameQuery {
+            snap: Snap::default(),
+            width: None,
+            height: None,
+            fit: Fit::default(),
+            crop: None,
+            format: None,
+            quality: None,
+        };
+
+        let format = |accept| query.render(&state, Some(accept)).map(|r| r.format);
+        assert_eq!(format("image/*").unwrap(), FormatKind::Png);
+        assert_eq!(
+            format("text/html").unwrap_err().0,
+            StatusCode::NOT_ACCEPTABLE
+        );
+    }
+
+    fn if_none_match(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(IF_NONE_MATCH, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn none_match_compares_weakly() {
+        let etag = "\"abc-1\"";
+        assert!(none_match(&if_none_match(&["\"abc-1\""]), etag));
+        assert!(none_match(&if_none_match(&["W/\"abc-1\""]), etag));
+        assert!(!none_match(&if_none_match(&["\"abc-2\""]), etag));
+        assert!(!none_match(&if_none_match(&[]), etag));
+    }
+
+    #[test]
+    fn none_match_reads_lists_and_star() {
+        let etag = "\"abc-1\"";
+        assert!(none_match(
+            &if_none_match(&["\"x\", W/\"abc-1\" ,\"y\""]),
+            etag
+        ));
+        assert!(none_match(&if_none_match(&["\"x\"", "\"abc-1\""]), etag));
+        assert!(none_match(&if_none_match(&["*"]), etag));
+        assert!(!none_match(&if_none_match(&["\"x\", \"y\""]), etag));
+    }
 }
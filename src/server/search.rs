@@ -0,0 +1,179 @@
+use crate::ffmpeg::subtitle::Subtitle;
+
+/// Subtitle cues for one stream, normalized once for repeated searching.
+#[derive(Debug)]
+pub struct SearchIndex {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    cue: Subtitle,
+
+    /// Normalized tokens joined by single spaces, padded with one on each
+    /// side so phrases can be matched on word boundaries
+    text: String,
+
+    tokens: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Hit<'a> {
+    pub cue: &'a Subtitle,
+    pub score: u32,
+}
+
+#[derive(Debug, Default)]
+struct Query {
+    phrases: Vec<String>,
+    terms: Vec<String>,
+}
+
+/// Lowercases and splits on anything that isn't alphanumeric, dropping
+/// apostrophes so that "don't" and "dont" are the same token.
+fn tokenize(s: &str) -> Vec<String> {
+    s.chars()
+        .filter(|c| *c != '\'' && *c != '’')
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+fn padded(tokens: &[String]) -> String {
+    format!(" {} ", tokens.join(" "))
+}
+
+impl Query {
+    /// Double-quoted sections are phrases that must match in order; the rest
+    /// are individual terms.
+    fn parse(q: &str) -> Self {
+        let mut query = Query::default();
+        for (n, part) in q.split('"').enumerate() {
+            let tokens = tokenize(part);
+            if tokens.is_empty() {
+                continue;
+            }
+            if n % 2 == 1 {
+                query.phrases.push(padded(&tokens));
+            } else {
+                query.terms.extend(tokens);
+            }
+        }
+        query
+    }
+
+    fn is_empty(&self) -> bool {
+        self.phrases.is_empty() && self.terms.is_empty()
+    }
+}
+
+impl Entry {
+    /// Every phrase and term must match. Exact words outrank prefixes, and
+    /// cues containing the terms in the order they were typed get a bonus.
+    fn score(&self, query: &Query) -> Option<u32> {
+        let mut score = 0;
+        for phrase in &query.phrases {
+            if !self.text.contains(phrase.as_str()) {
+                return None;
+            }
+            score += 10;
+        }
+
+        for term in &query.terms {
+            if self.tokens.iter().any(|t| t == term) {
+                score += 3;
+            } else if self.tokens.iter().any(|t| t.starts_with(term.as_str())) {
+                score += 1;
+            } else {
+                return None;
+            }
+        }
+
+        if query.terms.len() > 1 && self.text.contains(&padded(&query.terms)) {
+            score += 5;
+        }
+
+        Some(score)
+    }
+}
+
+impl SearchIndex {
+    pub fn new(cues: Vec<Subtitle>) -> Self {
+        let entries = cues
+            .into_iter()
+            .map(|cue| {
+                let tokens = tokenize(cue.content());
+                Entry {
+                    text: padded(&tokens),
+                    tokens,
+                    cue,
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn search(&self, q: &str, limit: usize) -> Vec<Hit<'_>> {
+        let query = Query::parse(q);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut hits: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|e| e.score(&query).map(|score| Hit { cue: &e.cue, score }))
+            .collect();
+
+        hits.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then(a.cue.start().cmp(&b.cue.start()))
+        });
+        hits.truncate(limit);
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::subtitle::parse_srt;
+
+    #[test]
+    fn parses_phrases_and_terms() {
+        let query = Query::parse(r#"Don't "open the DOOR" now"#);
+        assert_eq!(query.phrases, vec![" open the door "]);
+        assert_eq!(query.terms, vec!["dont", "now"]);
+        assert!(Query::parse(r#" "" ?! "#).is_empty());
+    }
+
+    #[test]
+    fn ranks_exact_words_and_order() {
+        let cues = parse_srt(concat!(
+            "1\n00:00:01,000 --> 00:00:02,000\nOpen the door, now!\n\n",
+            "2\n00:00:03,000 --> 00:00:04,000\nNow they're opening it\n\n",
+            "3\n00:00:05,000 --> 00:00:06,000\nNothing here\n",
+        ))
+        .unwrap();
+        let index = SearchIndex::new(cues);
+        let indices = |q| {
+            index
+                .search(q, 10)
+                .iter()
+                .map(|h| h.cue.index())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(indices("open now"), vec![1, 2]);
+        assert_eq!(indices(r#""the door""#), vec![1]);
+        assert_eq!(indices("theyre"), vec![2]);
+        assert!(indices("").is_empty());
+    }
+}
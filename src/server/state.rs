@@ -1,49 +1,175 @@
-use futures::{StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
 use lru::LruCache;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::info;
 
-use std::{convert::TryInto, env, io, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    env, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
 
-use crate::ffmpeg::{frame::Frame, frame_range::FrameRange, ErrorKind};
+use crate::ffmpeg::{
+    breaks::{BreakDetect, Breaks},
+    chapter::Chapter,
+    cmd::FormatKind,
+    cut::SmartCut,
+    frame::{Frame, Render},
+    frame_range::{FrameRange, FrameSet},
+    keyframe::{KeyframeIndex, Snap},
+    probe,
+    probe::Probe,
+    scene::{representative_frames, SceneChange, SceneDetect},
+    sequence::{Quality, Sequence},
+    sheet::{Layout, Sheet},
+    subtitle::{Subtitle, SubtitleStream},
+    ErrorKind,
+};
+
+use super::cache::{DiskCache, Fingerprint, SourceCache};
+use super::search::SearchIndex;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     source_file: String,
-    cache: Arc<Mutex<LruCache<usize, Frame>>>,
+    probe: Arc<RwLock<Arc<Probe>>>,
+    cache: Arc<Mutex<LruCache<(Render, usize), Frame>>>,
     image_processor: Arc<Mutex<()>>,
+    search_indexes: Arc<Mutex<HashMap<usize, Arc<SearchIndex>>>>,
+    audio_cache: Arc<Mutex<LruCache<AudioKey, Vec<u8>>>>,
+    scenes: Arc<Mutex<HashMap<u32, Arc<Vec<SceneChange>>>>>,
+    keyframes: Arc<Mutex<Option<Arc<KeyframeIndex>>>>,
+    breaks: Arc<Mutex<Option<Arc<Breaks>>>>,
+    fingerprint: Arc<Mutex<Option<Fingerprint>>>,
+    disk: Arc<DiskCache>,
 }
 
-fn cached_path_for(root: PathBuf, i: usize) -> PathBuf {
-    root.join(format!("{i}.png"))
+/// Audio snippets up to this long (ms) are cached once encoded.
+pub const AUDIO_SNIPPET_MAX: usize = 30_000;
+
+/// Shots rendered per ffmpeg run. Each one is a separate input, decoding
+/// in parallel, so this bounds memory rather than saving time.
+const SHOT_BATCH: usize = 16;
+
+type AudioKey = (usize, usize, usize, FormatKind);
+
+static CUT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Reads an analysis persisted by an earlier run, or runs `detect` on a
+/// blocking thread and persists its result.
+async fn load_or_detect<T, F>(persisted: PathBuf, detect: F) -> Result<T, ErrorKind>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce() -> Result<T, ErrorKind> + Send + 'static,
+{
+    match tokio::fs::read(&persisted).await {
+        Ok(bs) => serde_json::from_slice(&bs).map_err(|e| {
+            ErrorKind::Unhandled(format!(
+                "corrupt analysis file {}: {e}",
+                persisted.to_string_lossy()
+            ))
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let found = spawn_blocking(detect)
+                .await
+                .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??;
+            let bs = serde_json::to_vec(&found).map_err(|e| ErrorKind::Unhandled(e.to_string()))?;
+            tokio::fs::write(&persisted, bs).await?;
+            Ok(found)
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 impl AppState {
-    pub fn new(file: String, capacity: usize) -> Self {
+    pub fn new(file: String, probe: Probe, capacity: usize, disk: Arc<DiskCache>) -> Self {
         Self {
             source_file: file,
+            probe: Arc::new(RwLock::new(Arc::new(probe))),
             cache: Arc::new(Mutex::new(LruCache::new(capacity.try_into().unwrap()))),
             image_processor: Arc::new(Mutex::new(())),
+            search_indexes: Arc::new(Mutex::new(HashMap::new())),
+            audio_cache: Arc::new(Mutex::new(LruCache::new(64.try_into().unwrap()))),
+            scenes: Arc::new(Mutex::new(HashMap::new())),
+            keyframes: Arc::new(Mutex::new(None)),
+            breaks: Arc::new(Mutex::new(None)),
+            fingerprint: Arc::new(Mutex::new(None)),
+            disk,
         }
     }
 
-    pub fn file(&self) -> &str {
-        &self.source_file
+    /// What ffprobe reported for the source, as of its last change.
+    pub fn probe(&self) -> Arc<Probe> {
+        self.probe.read().unwrap().clone()
     }
 
+    /// The on-disk cache for the source as it is now. If the file has
+    /// changed since the last call, everything derived from the old version
+    /// is dropped first.
     #[tracing::instrument(skip_all)]
-    pub async fn frame_from_file(&self, i: usize) -> Result<Frame, ErrorKind> {
-        let cache_root = PathBuf::from(env::var("CACHE_DIR").unwrap());
-        if !cache_root.exists() || !cache_root.is_dir() {
-            return Err(ErrorKind::Unhandled(format!(
-                "Path to cache root \"{}\" either does not exist or is not a directory",
-                cache_root.to_string_lossy()
-            )));
+    async fn source_cache(&self) -> Result<SourceCache, ErrorKind> {
+        let current = Fingerprint::of(Path::new(&self.source_file))?;
+        let stale = {
+            let mut last = self.fingerprint.lock().await;
+            match last.replace(current.clone()) {
+                Some(previous) if previous != current => Some(previous),
+                _ => None,
+            }
+        };
+        if let Some(previous) = stale {
+            self.invalidate(&previous).await;
+        }
+        Ok(SourceCache::open(self.disk.root(), &current)?)
+    }
+
+    async fn invalidate(&self, previous: &Fingerprint) {
+        info!("{} changed, dropping its cache", self.source_file);
+        let dir = self.disk.root().join(previous.key());
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            info!("failed to remove {}: {e}", dir.to_string_lossy());
+        }
+        self.disk.forget_dir(&dir);
+
+        self.cache.lock().await.clear();
+        self.audio_cache.lock().await.clear();
+        self.search_indexes.lock().await.clear();
+        self.scenes.lock().await.clear();
+        *self.keyframes.lock().await = None;
+        *self.breaks.lock().await = None;
+
+        // the new version can differ in length, streams and start time
+        let file = self.source_file.clone();
+        match spawn_blocking(move || probe(&file)).await {
+            Ok(Ok(probe)) => *self.probe.write().unwrap() = Arc::new(probe),
+            Ok(Err(e)) => info!("failed to probe {}: {e}", self.source_file),
+            Err(_) => info!("failed to join probe of {}", self.source_file),
         }
-        let pb = cached_path_for(cache_root, i);
+    }
+
+    /// How frames are rendered when nothing else is asked for.
+    pub fn render(&self) -> Render {
+        Render {
+            format: self.disk.format(),
+            ..Render::default()
+        }
+    }
 
-        let mut frm = Frame::new(&self.source_file, i)?;
+    #[tracing::instrument(skip_all)]
+    pub async fn frame_from_file(&self, i: usize, render: Render) -> Result<Frame, ErrorKind> {
+        let pb = self.source_cache().await?.frame_path(&render, i);
+
+        let mut frm = Frame::new(&self.source_file, i, render)?;
 
-        match tokio::fs::read(pb).await {
-            Ok(bs) => frm.set_data(bs),
+        match tokio::fs::read(&pb).await {
+            Ok(bs) => {
+                self.disk.touch(&pb);
+                frm.set_data(bs)
+            }
             Err(e) if e.kind() == io::ErrorKind::NotFound => {}
             Err(e) => return Err(e.into()),
         }
@@ -51,22 +177,33 @@ impl AppState {
         Ok(frm)
     }
 
+    /// Counts frames or audio just written to disk against the cache budget.
+    async fn record_on_disk(&self, paths: Vec<PathBuf>) -> Result<(), ErrorKind> {
+        let disk = self.disk.clone();
+        spawn_blocking(move || disk.record(paths))
+            .await
+            .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn ingest_frame_range(
         &self,
         start: usize,
         n: usize,
         step: usize,
-    ) -> Result<(), ErrorKind> {
-        let cache_root = PathBuf::from(env::var("CACHE_DIR").unwrap());
+    ) -> Result<Vec<usize>, ErrorKind> {
+        let render = self.render();
+        let source_cache = self.source_cache().await?;
+        let dir = source_cache.frames_dir(&render)?;
         let output = {
             let _g = self.image_processor.lock().await;
             let mut range = FrameRange::new(
                 &self.source_file,
-                &cache_root.to_string_lossy(),
+                &dir.to_string_lossy(),
                 start,
                 n,
                 step,
+                render.clone(),
             )?;
             spawn_blocking(|| async move { range.read() })
                 .await
@@ -74,45 +211,394 @@ impl AppState {
         }
         .await?;
 
-        futures::stream::iter(output)
-            .map(|code| self.frame_from_file(code))
+        self.load_ingested(&source_cache, &render, &output).await?;
+        Ok(output)
+    }
+
+    /// Counts freshly rendered frames against the disk budget and loads them
+    /// into memory.
+    async fn load_ingested(
+        &self,
+        source_cache: &SourceCache,
+        render: &Render,
+        codes: &[usize],
+    ) -> Result<(), ErrorKind> {
+        self.record_on_disk(
+            codes
+                .iter()
+                .map(|code| source_cache.frame_path(render, *code))
+                .collect(),
+        )
+        .await?;
+
+        futures::stream::iter(codes.to_vec())
+            .map(|code| self.frame_from_file(code, render.clone()))
             .buffer_unordered(4)
             .try_for_each(|frm| async {
                 let mut cache = self.cache.lock().await;
-                cache.push(frm.timecode(), frm);
+                cache.push((render.clone(), frm.timecode()), frm);
                 Ok(())
             })
-            .await?;
+            .await
+    }
+
+    /// Like `ingest_frame_range`, but samples the middle of each shot in
+    /// range rather than at a fixed step. Shots are rendered in batches, one
+    /// ffmpeg run each.
+    #[tracing::instrument(skip(self))]
+    pub async fn ingest_shots(
+        &self,
+        from: usize,
+        to: usize,
+        n: usize,
+        threshold: f32,
+    ) -> Result<Vec<usize>, ErrorKind> {
+        let changes = self.scenes(threshold).await?;
+        let output = representative_frames(&changes, from, to, n);
 
-        Ok(())
+        let render = self.render();
+        let source_cache = self.source_cache().await?;
+        let dir = source_cache.frames_dir(&render)?;
+        for batch in output.chunks(SHOT_BATCH) {
+            let set = FrameSet::new(
+                &self.source_file,
+                &dir.to_string_lossy(),
+                batch,
+                render.clone(),
+            )?;
+            let _g = self.image_processor.lock().await;
+            spawn_blocking(move || set.read())
+                .await
+                .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??;
+        }
+
+        self.load_ingested(&source_cache, &render, &output).await?;
+        Ok(output)
     }
 
+    /// The ETag for frame `i` rendered with `render`, without rendering it.
+    pub async fn frame_etag(&self, i: usize, render: &Render) -> Result<String, ErrorKind> {
+        Ok(self.source_cache().await?.frame_etag(render, i))
+    }
+
+    /// Serves a frame from memory, then disk, rendering and storing it if
+    /// it's in neither.
     #[tracing::instrument(skip_all)]
-    pub async fn request_frame(&self, i: usize) -> Result<Vec<u8>, ErrorKind> {
-        let mut frame = self.frame_from_file(i).await?;
-        if frame.has_data() {
-            let mut cache = self.cache.lock().await;
-            cache.push(i, frame.clone());
-        } else {
+    pub async fn request_frame(&self, i: usize, render: Render) -> Result<Vec<u8>, ErrorKind> {
+        // also checks the source for changes, which clears the memory cache
+        let pb = self.source_cache().await?.frame_path(&render, i);
+        let key = (render, i);
+
+        {
             let mut cache = self.cache.lock().await;
-            if let Some(frame) = cache.get_mut(&i) {
+            if let Some(frame) = cache.get_mut(&key) {
                 if frame.has_data() {
+                    self.disk.touch(&pb);
                     return frame.write();
                 }
             }
         }
 
-        {
-            let _g = self.image_processor.lock().await;
-            let mut fc = frame.clone();
-            tokio::task::spawn_blocking(move || fc.read())
-                .await
-                .map_err(|_| ErrorKind::Unhandled("oops".into()))??;
+        let mut frame = self.frame_from_file(i, key.0.clone()).await?;
+        if !frame.has_data() {
+            frame = {
+                let _g = self.image_processor.lock().await;
+                spawn_blocking(move || frame.read().map(|_| frame))
+                    .await
+                    .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??
+            };
+
+            if let Some(dir) = pb.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&pb, frame.write()?).await?;
+            self.record_on_disk(vec![pb]).await?;
         }
 
         let mut cache = self.cache.lock().await;
-
-        cache.push(i, frame.clone());
+        cache.push(key, frame.clone());
         frame.write()
     }
+
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.probe().chapters()
+    }
+
+    pub fn subtitle_streams(&self) -> Vec<SubtitleStream> {
+        self.probe().subtitle_streams()
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn subtitles(
+        &self,
+        from: usize,
+        to: usize,
+        stream: SubtitleStream,
+    ) -> Result<Vec<Subtitle>, ErrorKind> {
+        let seq = Sequence::subtitles(&self.source_file, from, to).with_stream(stream);
+        spawn_blocking(move || seq.read_subtitles())
+            .await
+            .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))?
+    }
+
+    /// Has ffmpeg write the cues in range as WebVTT, timed from the start of
+    /// the file.
+    #[tracing::instrument(skip(self))]
+    pub async fn subtitle_track(
+        &self,
+        from: usize,
+        to: usize,
+        stream: SubtitleStream,
+    ) -> Result<String, ErrorKind> {
+        let seq = Sequence::subtitles(&self.source_file, from, to)
+            .with_stream(stream)
+            .with_format(FormatKind::WebVtt);
+        spawn_blocking(move || seq.read_text())
+            .await
+            .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))?
+    }
+
+    /// Extracts every cue of `stream` on first use and keeps them indexed
+    /// for the lifetime of the server.
+    #[tracing::instrument(skip(self))]
+    pub async fn search_index(
+        &self,
+        stream: SubtitleStream,
+    ) -> Result<Arc<SearchIndex>, ErrorKind> {
+        if let Some(index) = self.search_indexes.lock().await.get(&stream.index) {
+            return Ok(index.clone());
+        }
+
+        // extracted without holding the lock, so searches of other streams
+        // aren't held up; a concurrent search of the same stream may extract
+        // it too, and whichever finishes first is kept
+        let end = self
+            .probe()
+            .duration()
+            .ok_or_else(|| ErrorKind::Unhandled("source has no duration".into()))?;
+        let key = stream.index;
+        let cues = self
+            .subtitles(0, end.as_millis() as usize + 1, stream)
+            .await?;
+
+        let index = Arc::new(SearchIndex::new(cues));
+        let mut indexes = self.search_indexes.lock().await;
+        Ok(indexes.entry(key).or_insert(index).clone())
+    }
+
+    /// Starts encoding an MP4 of the range, yielding it as it's written.
+    #[tracing::instrument(skip(self))]
+    pub fn clip(
+        &self,
+        from: usize,
+        to: usize,
+        quality: Quality,
+        width: Option<u32>,
+    ) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, ErrorKind> {
+        Sequence::video(&self.source_file, from, to)
+            .with_quality(quality)
+            .with_width(width)
+            .stream()
+    }
+
+    /// Copies the range between keyframes and re-encodes only its edges.
+    #[tracing::instrument(skip(self))]
+    pub async fn smart_cut(&self, from: usize, to: usize) -> Result<SmartCut, ErrorKind> {
+        let workdir = env::temp_dir().join(format!(
+            "frm-cut-{}-{}",
+            std::process::id(),
+            CUT_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let seq = Sequence::video(&self.source_file, from, to);
+        let start_time = self.probe().start_time().unwrap_or_default();
+        spawn_blocking(move || seq.smart_cut(workdir, start_time))
+            .await
+            .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))?
+    }
+
+    /// Encodes a short audio snippet, or returns it from memory or disk.
+    #[tracing::instrument(skip(self))]
+    pub async fn request_audio(
+        &self,
+        from: usize,
+        to: usize,
+        stream: usize,
+        format: FormatKind,
+    ) -> Result<Vec<u8>, ErrorKind> {
+        // also checks the source for changes, which clears the memory cache
+        let pb = self
+            .source_cache()
+            .await?
+            .audio_path(stream, from, to, &format);
+        let key = (from, to, stream, format.clone());
+        if let Some(bytes) = self.audio_cache.lock().await.get(&key) {
+            self.disk.touch(&pb);
+            return Ok(bytes.clone());
+        }
+
+        let bytes = match tokio::fs::read(&pb).await {
+            Ok(bytes) => {
+                self.disk.touch(&pb);
+                bytes
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let seq = Sequence::audio(&self.source_file, from, to)
+                    .with_audio_stream(stream)
+                    .with_format(format);
+                let bytes = spawn_blocking(move || seq.read_bytes())
+                    .await
+                    .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??;
+
+                if let Some(dir) = pb.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                tokio::fs::write(&pb, &bytes).await?;
+                self.record_on_disk(vec![pb]).await?;
+                bytes
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.audio_cache.lock().await.push(key, bytes.clone());
+        Ok(bytes)
+    }
+
+    /// Starts encoding the range's audio, yielding it as it's written.
+    #[tracing::instrument(skip(self))]
+    pub fn audio(
+        &self,
+        from: usize,
+        to: usize,
+        stream: usize,
+        format: FormatKind,
+    ) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, ErrorKind> {
+        Sequence::audio(&self.source_file, from, to)
+            .with_audio_stream(stream)
+            .with_format(format)
+            .stream()
+    }
+
+    /// Renders a contact sheet of the range; `sheet_layout` says where each
+    /// frame landed in it.
+    #[tracing::instrument(skip(self))]
+    pub async fn sheet(
+        &self,
+        from: usize,
+        to: usize,
+        n: usize,
+        width: u32,
+        captions: bool,
+    ) -> Result<Vec<u8>, ErrorKind> {
+        let file = self.source_file.clone();
+        let aspect = self.sheet_aspect()?;
+        let _g = self.image_processor.lock().await;
+        spawn_blocking(move || Sheet::new(&file, from, to, n, width, aspect, captions)?.read())
+            .await
+            .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))?
+    }
+
+    /// Where each frame of a contact sheet would land, without rendering it.
+    #[tracing::instrument(skip(self))]
+    pub async fn sheet_layout(
+        &self,
+        from: usize,
+        to: usize,
+        n: usize,
+        width: u32,
+        captions: bool,
+    ) -> Result<Layout, ErrorKind> {
+        let file = self.source_file.clone();
+        let aspect = self.sheet_aspect()?;
+        spawn_blocking(
+            move || Ok(Sheet::new(&file, from, to, n, width, aspect, captions)?.layout()),
+        )
+        .await
+        .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))?
+    }
+
+    /// Display aspect ratio of the video, which sheet tiles are shaped by.
+    fn sheet_aspect(&self) -> Result<f64, ErrorKind> {
+        self.probe()
+            .video()
+            .and_then(|video| video.display_aspect_ratio())
+            .filter(|aspect| *aspect > 0.0)
+            .ok_or_else(|| ErrorKind::Unhandled("no video stream to sample".into()))
+    }
+
+    /// Shot boundaries across the whole file. Detection decodes every frame,
+    /// so results are kept in memory and persisted next to the frame cache.
+    #[tracing::instrument(skip(self))]
+    pub async fn scenes(&self, threshold: f32) -> Result<Arc<Vec<SceneChange>>, ErrorKind> {
+        // also rejects NaN, which would otherwise round to the key for 0
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(ErrorKind::ArgumentError);
+        }
+        let key = (threshold * 1000.0).round() as u32;
+
+        // checked before locking, since a changed source clears `scenes`
+        let persisted = self
+            .source_cache()
+            .await?
+            .analysis_path(&format!("scenes-{key}"));
+
+        // held across detection so concurrent requests don't detect twice
+        let mut scenes = self.scenes.lock().await;
+        if let Some(changes) = scenes.get(&key) {
+            return Ok(changes.clone());
+        }
+
+        let detect = SceneDetect::new(
+            &self.source_file,
+            key as f32 / 1000.0,
+            self.probe().start_time().unwrap_or_default(),
+        )?;
+        let changes = load_or_detect(persisted, move || detect.read()).await?;
+
+        let changes = Arc::new(changes);
+        scenes.insert(key, changes.clone());
+        Ok(changes)
+    }
+
+    /// Black frames, silences and the breaks where both coincide, across the
+    /// whole file. Kept like scenes, since detection decodes everything.
+    #[tracing::instrument(skip(self))]
+    pub async fn breaks(&self) -> Result<Arc<Breaks>, ErrorKind> {
+        let persisted = self.source_cache().await?.analysis_path("breaks");
+
+        let mut breaks = self.breaks.lock().await;
+        if let Some(found) = breaks.as_ref() {
+            return Ok(found.clone());
+        }
+
+        let detect = BreakDetect::new(
+            &self.source_file,
+            self.probe().start_time().unwrap_or_default(),
+        )?;
+        let found = Arc::new(load_or_detect(persisted, move || detect.read()).await?);
+        *breaks = Some(found.clone());
+        Ok(found)
+    }
+
+    /// Maps a requested timecode onto the one that will actually be served,
+    /// indexing the file's keyframes on first use.
+    #[tracing::instrument(skip(self))]
+    pub async fn snap(&self, at: usize, snap: Snap) -> Result<usize, ErrorKind> {
+        if let Snap::Exact = snap {
+            return Ok(at);
+        }
+
+        let mut keyframes = self.keyframes.lock().await;
+        let index = match keyframes.as_ref() {
+            Some(index) => index.clone(),
+            None => {
+                let file = self.source_file.clone();
+                let start_time = self.probe().start_time().unwrap_or_default();
+                let index = spawn_blocking(move || KeyframeIndex::probe(&file, start_time))
+                    .await
+                    .map_err(|_| ErrorKind::Unhandled("failed to join blocking task".into()))??;
+                keyframes.insert(Arc::new(index)).clone()
+            }
+        };
+        Ok(index.snap(at, snap))
+    }
 }